@@ -0,0 +1,103 @@
+//! Measures chat throughput as the number of rooms grows. Every room gets the
+//! same load, so with rooms running in parallel the total throughput should
+//! rise with the room count until the CPUs are saturated. Rooms record into a
+//! log in the temporary directory, as a server would, and the time includes
+//! flushing it.
+//!
+//! Usage: bench [max rooms] [messages per sender]
+
+use room_chat_app::common::{ChatError, Message};
+use room_chat_app::server::client_manager::ClientManager;
+use room_chat_app::server::room_manager::RoomManager;
+use room_chat_app::server::storage::LogStorage;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const MEMBERS_PER_ROOM: usize = 10;
+const SENDERS_PER_ROOM: usize = 2;
+
+/// Sends `messages` chat lines from each sender in `rooms` rooms and returns
+/// how long it took.
+async fn run(rooms: usize, messages: usize) -> Result<Duration, ChatError> {
+    let log = env::temp_dir().join(format!("room-chat-bench-{}.log", std::process::id()));
+    let _ = fs::remove_file(&log);
+    let result = run_logged(rooms, messages, LogStorage::open(&log)?).await;
+    let _ = fs::remove_file(&log);
+    result
+}
+
+async fn run_logged(
+    rooms: usize,
+    messages: usize,
+    storage: LogStorage,
+) -> Result<Duration, ChatError> {
+    let clients = Arc::new(Mutex::new(ClientManager::new()));
+    let room_manager = Arc::new(RoomManager::new(Arc::clone(&clients)).load(Box::new(storage))?);
+    let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+
+    for r in 0..rooms {
+        let room = format!("bench{}", r);
+        room_manager.create_room(room.clone(), None).await?;
+        for m in 0..MEMBERS_PER_ROOM {
+            let username = format!("user{}_{}", r, m);
+            let mut outbox = clients.lock().await.add_client(username.clone(), addr).await?;
+            // Stands in for the connection handler writing to the socket
+            tokio::spawn(async move { while outbox.recv().await.is_some() {} });
+            room_manager.join_room(&username, &room, None).await?;
+        }
+    }
+
+    let start = Instant::now();
+    let mut senders = Vec::new();
+    for r in 0..rooms {
+        for s in 0..SENDERS_PER_ROOM {
+            let room_manager = Arc::clone(&room_manager);
+            senders.push(tokio::spawn(async move {
+                let room = format!("bench{}", r);
+                let sender = format!("user{}_{}", r, s);
+                for i in 0..messages {
+                    let message = Message::new(room.clone(), sender.clone(), i.to_string());
+                    room_manager.broadcast_message(message).await?;
+                }
+                Ok::<(), ChatError>(())
+            }));
+        }
+    }
+    for sender in senders {
+        sender.await??;
+    }
+    room_manager.flush().await?;
+    Ok(start.elapsed())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), ChatError> {
+    let max_rooms: usize = env::args().nth(1).and_then(|a| a.parse().ok()).unwrap_or(16);
+    let messages: usize = env::args().nth(2).and_then(|a| a.parse().ok()).unwrap_or(5000);
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    println!(
+        "{} members and {} senders per room, {} messages per sender, {} CPUs",
+        MEMBERS_PER_ROOM, SENDERS_PER_ROOM, messages, cpus
+    );
+    println!("{:>6} {:>12} {:>14} {:>16}", "rooms", "seconds", "messages/s", "deliveries/s");
+
+    let mut rooms = 1;
+    while rooms <= max_rooms {
+        let elapsed = run(rooms, messages).await?.as_secs_f64();
+        let total = (rooms * SENDERS_PER_ROOM * messages) as f64;
+        println!(
+            "{:>6} {:>12.3} {:>14.0} {:>16.0}",
+            rooms,
+            elapsed,
+            total / elapsed,
+            total * MEMBERS_PER_ROOM as f64 / elapsed
+        );
+        rooms *= 2;
+    }
+    Ok(())
+}
@@ -1,23 +1,40 @@
-use room_chat_app::client::ChatClient;
+use room_chat_app::client::{ChatClient, ClientConfig};
 use room_chat_app::common::ChatError;
-use std::env;
+use std::process;
 
 #[tokio::main]
 async fn main() -> Result<(), ChatError> {
-    let username = env::args()
-        .nth(1)
-        .expect("Please provide a username as argument");
+    let config = ClientConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let addr = format!("{}:{}", config.host, config.port);
+    let client = ChatClient::new(config).await.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    let addr = "127.0.0.1:8080";
-    let mut client = ChatClient::new(addr, username).await?;
-    
     println!("Connected to server at {}", addr);
     println!("Commands:");
-    println!("  /join <room>  - Join a chat room");
+    println!("  /join <room> [password] - Join a chat room");
     println!("  /leave <room> - Leave a chat room");
+    println!("  /create <room> - Create a chat room you own (registered nicknames only)");
+    println!("  /rename <old> <new> - Rename a room you own");
+    println!("  /delete <room> - Delete a room you own");
+    println!("  /op <user>, /deop <user> - Grant or revoke moderator to a registered user");
+    println!("  /kick <user> [reason] - Remove a user from the current room");
+    println!("  /ban <user> [duration] - Ban a user, e.g. /ban bob 10m");
+    println!("  /unban <user> - Lift a ban");
+    println!("  /invite <user> - Invite a registered user to the current room");
+    println!("  /mode <+i|-i|+h|-h|+k <password>|-k> - Invite-only, hidden, password");
     println!("  /list        - List available rooms");
+    println!("  /msg <user> <text> - Send a private message");
     println!("  /users <room> - List users in a room");
-    println!("  /quit        - Quit the application");
+    println!("  /history <room> [n] - Show older messages");
+    println!("  /register <password> - Register your current nickname");
+    println!("  /find [text] - Find text in the current room; /find alone clears the search");
+    println!("  /quit [reason] - Quit the application");
     
     client.run().await?;
     Ok(())
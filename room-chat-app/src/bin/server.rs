@@ -1,14 +1,20 @@
-use room_chat_app::server::ChatServer;
+use room_chat_app::server::{ChatServer, ServerConfig};
 use room_chat_app::common::ChatError;
+use std::process;
 
 #[tokio::main]
 async fn main() -> Result<(), ChatError> {
-    let addr = "127.0.0.1:8080";
-    
+    let config = ServerConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
     println!("Starting chat server...");
-    let server = ChatServer::new(addr).await?;
-    println!("Server listening on {}", addr);
-    
+    let server = ChatServer::new(config).await.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
     server.run().await?;
     Ok(())
-}
\ No newline at end of file
+}
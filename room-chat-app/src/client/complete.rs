@@ -0,0 +1,131 @@
+/// Every command the input box understands, `/find` being the client's own.
+const COMMANDS: &[&str] = &[
+    "/ban", "/create", "/delete", "/deop", "/find", "/history", "/invite", "/join", "/kick",
+    "/leave", "/list", "/mode", "/msg", "/op", "/quit", "/register", "/rename", "/unban",
+    "/users",
+];
+
+/// Commands whose first argument is a room.
+const ROOM_COMMANDS: &[&str] = &["/delete", "/history", "/join", "/leave", "/rename", "/users"];
+
+/// Commands whose first argument is a nickname.
+const USER_COMMANDS: &[&str] = &["/ban", "/deop", "/invite", "/kick", "/msg", "/op", "/unban"];
+
+/// Candidates for the word before the cursor, cycled through by pressing
+/// Tab again.
+pub struct Completion {
+    /// Where the word being completed starts in the input.
+    start: usize,
+    /// Each candidate with what follows it once completed.
+    candidates: Vec<String>,
+    at: usize,
+}
+
+/// Those of `names` starting with `prefix`, ignoring case, in order.
+fn starting_with(names: &[String], prefix: &str, suffix: &str) -> Vec<String> {
+    let prefix = prefix.to_lowercase();
+    let mut matches: Vec<String> = names
+        .iter()
+        .filter(|name| name.to_lowercase().starts_with(&prefix))
+        .map(|name| format!("{}{}", name, suffix))
+        .collect();
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+impl Completion {
+    /// Looks for ways to complete the word ending at `cursor` in `input`:
+    /// a command if it is the first word and starts with `/`, a room or a
+    /// nickname if it is the first argument of a command taking one, and
+    /// otherwise a nickname. Returns `None` if nothing fits.
+    pub fn new(input: &str, cursor: usize, rooms: &[String], nicknames: &[String]) -> Option<Self> {
+        let before = &input[..cursor];
+        let start = before
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + before[i..].chars().next().map_or(1, char::len_utf8));
+        let word = &before[start..];
+        let earlier: Vec<&str> = before[..start].split_whitespace().collect();
+
+        let candidates = match earlier.as_slice() {
+            [] if word.starts_with('/') => COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| format!("{} ", command))
+                .collect(),
+            // Addressing someone at the start of a line
+            [] => starting_with(nicknames, word, ": "),
+            [command] if ROOM_COMMANDS.contains(command) => starting_with(rooms, word, " "),
+            [command] if USER_COMMANDS.contains(command) => starting_with(nicknames, word, " "),
+            _ => starting_with(nicknames, word, " "),
+        };
+        (!candidates.is_empty()).then_some(Completion {
+            start,
+            candidates,
+            at: 0,
+        })
+    }
+
+    /// Where the completed word starts.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// The candidate to put in place of the word.
+    pub fn current(&self) -> &str {
+        &self.candidates[self.at]
+    }
+
+    /// Moves `step` candidates on, going round at either end.
+    pub fn cycle(&mut self, step: isize) {
+        let len = self.candidates.len() as isize;
+        self.at = (self.at as isize + step).rem_euclid(len) as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn completes_after_wide_whitespace() {
+        let nicknames = names(&["alice", "bob"]);
+        for input in ["hi\u{3000}al", "hi\u{a0}al"] {
+            let completion = Completion::new(input, input.len(), &[], &nicknames).unwrap();
+            assert_eq!(completion.start(), input.len() - 2);
+            assert_eq!(completion.current(), "alice ");
+        }
+    }
+
+    #[test]
+    fn completes_by_position() {
+        let rooms = names(&["lobby", "games"]);
+        let nicknames = names(&["alice", "bob"]);
+        let complete = |input: &str| {
+            let completion = Completion::new(input, input.len(), &rooms, &nicknames)?;
+            Some(completion.current().to_string())
+        };
+        assert_eq!(complete("/jo").as_deref(), Some("/join "));
+        assert_eq!(complete("/join lo").as_deref(), Some("lobby "));
+        assert_eq!(complete("/msg b").as_deref(), Some("bob "));
+        assert_eq!(complete("Al").as_deref(), Some("alice: "));
+        assert_eq!(complete("zz"), None);
+    }
+
+    #[test]
+    fn cycles_through_candidates() {
+        let rooms = names(&["games", "garden"]);
+        let mut completion = Completion::new("/join ga", 8, &rooms, &[]).unwrap();
+        assert_eq!(completion.current(), "games ");
+        completion.cycle(1);
+        assert_eq!(completion.current(), "garden ");
+        completion.cycle(1);
+        assert_eq!(completion.current(), "games ");
+        completion.cycle(-1);
+        assert_eq!(completion.current(), "garden ");
+    }
+}
@@ -0,0 +1,416 @@
+use crate::common::config::{self, invalid};
+use crate::common::protocol::{validate_nickname, validate_room_name};
+use crate::common::ChatError;
+use clap::{Parser, ValueEnum};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Environment variable the password is read from, overriding the config
+/// file. It is never taken on the command line, where `ps` and the shell
+/// history would show it.
+pub const PASSWORD_VAR: &str = "ROOM_CHAT_PASSWORD";
+
+/// Command-line options. Each overrides the matching setting of the config
+/// file.
+#[derive(Debug, Parser)]
+#[command(
+    about = "Connects to a room chat server",
+    after_help = "The password of a registered nickname, or one to register a free nickname \
+                  with, is read from $ROOM_CHAT_PASSWORD or the config file's `password`."
+)]
+pub struct ClientArgs {
+    /// Nickname to log in as
+    nickname: Option<String>,
+    /// Config file to read instead of the one in the user's config directory
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Server to connect to
+    #[arg(long, value_name = "HOST")]
+    host: Option<String>,
+    /// Port the server listens on
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Room to join after logging in, may be given more than once
+    #[arg(short, long = "join", value_name = "ROOM")]
+    join: Vec<String>,
+    /// Colours to draw the UI in
+    #[arg(long, value_enum)]
+    theme: Option<Theme>,
+}
+
+/// Colours the UI is drawn in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// For terminals with a dark background
+    #[default]
+    Dark,
+    /// For terminals with a light background
+    Light,
+    /// The terminal's own colours only
+    Mono,
+}
+
+/// A key, possibly with modifiers, written like `esc`, `ctrl+q` or `alt+1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyBinding {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    pub const fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        KeyBinding { code, modifiers }
+    }
+
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        let mut modifiers = key.modifiers;
+        // Shift is already part of the character typed
+        if let KeyCode::Char(_) = key.code {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        key.code == self.code && modifiers == self.modifiers
+    }
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').collect();
+        // "ctrl++" binds the plus key, as does "+" on its own
+        if s == "+" || s.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+        let key = parts.pop().filter(|key| !key.is_empty());
+        let key = key.ok_or_else(|| format!("Key binding `{}` names no key", s))?;
+
+        let mut modifiers = KeyModifiers::NONE;
+        for modifier in parts {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("Unknown modifier `{}` in `{}`", modifier, s)),
+            };
+        }
+
+        let mut chars = key.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c.to_ascii_lowercase()),
+            _ => match key.to_lowercase().as_str() {
+                "enter" | "return" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" | "ins" => KeyCode::Insert,
+                "space" => KeyCode::Char(' '),
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                f => match f.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => return Err(format!("Unknown key `{}` in `{}`", key, s)),
+                },
+            },
+        };
+        Ok(KeyBinding { code, modifiers })
+    }
+}
+
+impl TryFrom<String> for KeyBinding {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl"),
+            (KeyModifiers::ALT, "Alt"),
+            (KeyModifiers::SHIFT, "Shift"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// What each UI action is bound to, the `[keys]` table of the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    /// Sends the input line.
+    pub send: KeyBinding,
+    /// Starts a new line in the input without sending it.
+    pub newline: KeyBinding,
+    /// Completes the command, room or nickname being typed, or on further
+    /// presses offers the next candidate.
+    pub complete: KeyBinding,
+    pub quit: KeyBinding,
+    /// Shows the next joined room.
+    pub next_room: KeyBinding,
+    pub prev_room: KeyBinding,
+    /// Scroll the message pane a page at a time.
+    pub scroll_up: KeyBinding,
+    pub scroll_down: KeyBinding,
+    /// Scroll to the oldest message, or back to the newest.
+    pub scroll_top: KeyBinding,
+    pub scroll_bottom: KeyBinding,
+    /// Starts a `/find`, or jumps to the next older match of the last one.
+    pub find: KeyBinding,
+}
+
+impl KeyBindings {
+    /// Every binding with the name it has in the config file.
+    pub fn all(&self) -> Vec<(&'static str, KeyBinding)> {
+        vec![
+            ("send", self.send),
+            ("newline", self.newline),
+            ("complete", self.complete),
+            ("quit", self.quit),
+            ("next_room", self.next_room),
+            ("prev_room", self.prev_room),
+            ("scroll_up", self.scroll_up),
+            ("scroll_down", self.scroll_down),
+            ("scroll_top", self.scroll_top),
+            ("scroll_bottom", self.scroll_bottom),
+            ("find", self.find),
+        ]
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            send: KeyBinding::new(KeyCode::Enter, KeyModifiers::NONE),
+            newline: KeyBinding::new(KeyCode::Enter, KeyModifiers::SHIFT),
+            complete: KeyBinding::new(KeyCode::Tab, KeyModifiers::NONE),
+            quit: KeyBinding::new(KeyCode::Esc, KeyModifiers::NONE),
+            next_room: KeyBinding::new(KeyCode::Char('n'), KeyModifiers::CONTROL),
+            prev_room: KeyBinding::new(KeyCode::Char('p'), KeyModifiers::CONTROL),
+            scroll_up: KeyBinding::new(KeyCode::PageUp, KeyModifiers::NONE),
+            scroll_down: KeyBinding::new(KeyCode::PageDown, KeyModifiers::NONE),
+            scroll_top: KeyBinding::new(KeyCode::Home, KeyModifiers::CONTROL),
+            scroll_bottom: KeyBinding::new(KeyCode::End, KeyModifiers::CONTROL),
+            find: KeyBinding::new(KeyCode::Char('f'), KeyModifiers::CONTROL),
+        }
+    }
+}
+
+/// Everything a `ChatClient` can be told, read from a TOML file whose keys
+/// match the field names. Missing keys keep their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    pub nickname: Option<String>,
+    pub password: Option<String>,
+    /// Rooms joined right after logging in, the first one shown.
+    pub auto_join: Vec<String>,
+    pub theme: Theme,
+    /// Where sent lines are kept for recalling with Up and Down.
+    pub history_file: Option<PathBuf>,
+    pub keys: KeyBindings,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            nickname: None,
+            password: None,
+            auto_join: Vec::new(),
+            theme: Theme::default(),
+            history_file: Self::default_history_path(),
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// The config file read when none is given, `room-chat/client.toml` in
+    /// the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("room-chat").join("client.toml"))
+    }
+
+    /// The history file used when none is set, `room-chat/history` in the
+    /// user's data directory.
+    pub fn default_history_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("room-chat").join("history"))
+    }
+
+    /// Builds the configuration from the command line and the config file,
+    /// and validates it.
+    pub fn from_args() -> Result<Self, ChatError> {
+        Self::from_cli(ClientArgs::parse())
+    }
+
+    pub fn from_cli(args: ClientArgs) -> Result<Self, ChatError> {
+        let mut config = match (&args.config, Self::default_path()) {
+            (Some(path), _) => config::load(path)?,
+            // The default file is optional
+            (None, Some(path)) => match config::load(&path) {
+                Err(_) if !path.exists() => Self::default(),
+                loaded => loaded?,
+            },
+            (None, None) => Self::default(),
+        };
+
+        config.host = args.host.unwrap_or(config.host);
+        config.port = args.port.unwrap_or(config.port);
+        config.nickname = args.nickname.or(config.nickname);
+        config.password = std::env::var(PASSWORD_VAR).ok().or(config.password);
+        if !args.join.is_empty() {
+            config.auto_join = args.join;
+        }
+        config.theme = args.theme.unwrap_or(config.theme);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ChatError> {
+        if self.host.is_empty() {
+            return Err(invalid("host cannot be empty".to_string()));
+        }
+        if self.port == 0 {
+            return Err(invalid("port must be between 1 and 65535".to_string()));
+        }
+        let nickname = self.nickname.as_deref().ok_or_else(|| {
+            invalid("No nickname given, pass one or set `nickname` in the config file".to_string())
+        })?;
+        validate_nickname(nickname).map_err(|e| invalid(format!("nickname: {}", e.message)))?;
+        for room in &self.auto_join {
+            validate_room_name(room)
+                .map_err(|e| invalid(format!("auto_join: {}: {}", room, e.message)))?;
+        }
+        let bindings = self.keys.all();
+        for (i, (name, key)) in bindings.iter().enumerate() {
+            if let Some((other, _)) = bindings[..i].iter().find(|(_, k)| k == key) {
+                let message = format!("keys: {} is bound to both {} and {}", key, other, name);
+                return Err(invalid(message));
+            }
+        }
+        Ok(())
+    }
+
+    /// The nickname to log in as. Always set once validated.
+    pub fn nickname(&self) -> &str {
+        self.nickname.as_deref().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `flags` as the client's command line, with `--config` pointing
+    /// at a file holding `toml`.
+    fn from_cli(test: &str, toml: &str, flags: &[&str]) -> Result<ClientConfig, ChatError> {
+        let file = format!("room-chat-{}-{}.toml", test, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        std::fs::write(&path, toml).unwrap();
+        let mut argv = vec!["client".to_string(), "--config".to_string()];
+        argv.push(path.display().to_string());
+        argv.extend(flags.iter().map(|flag| flag.to_string()));
+        let config = ClientConfig::from_cli(ClientArgs::try_parse_from(argv).unwrap());
+        let _ = std::fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn lets_flags_override_the_config_file() {
+        let toml = "host = \"chat.example\"\nport = 7000\nnickname = \"alice\"\n\
+                    auto_join = [\"lobby\"]\ntheme = \"light\"\n";
+        let flags = ["bob", "--port", "7001", "--join", "rust", "--join", "games"];
+        let config = from_cli("client-flags", toml, &flags).unwrap();
+        assert_eq!(config.nickname(), "bob");
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.auto_join, ["rust", "games"]);
+        // What no flag was given for comes from the file
+        assert_eq!(config.host, "chat.example");
+        assert_eq!(config.theme, Theme::Light);
+    }
+
+    #[test]
+    fn refuses_an_action_bound_twice() {
+        let toml = "nickname = \"alice\"\n[keys]\nfind = \"ctrl+n\"\n";
+        let error = from_cli("duplicate-keys", toml, &[]).unwrap_err();
+        assert_eq!(error.message, "keys: Ctrl+N is bound to both next_room and find");
+    }
+
+    #[test]
+    fn refuses_invalid_settings() {
+        let cases = [
+            "port = 7000\n",
+            "nickname = \"System\"\n",
+            "nickname = \"alice\"\nauto_join = [\"two words\"]\n",
+            "nickname = \"alice\"\n[keys]\nquit = \"hyper+q\"\n",
+            "nickname = \"alice\"\ncolour = \"red\"\n",
+        ];
+        for (i, toml) in cases.into_iter().enumerate() {
+            let test = format!("invalid-client-config-{}", i);
+            let error = from_cli(&test, toml, &[]).unwrap_err();
+            assert_eq!(error.kind, crate::common::ChatErrorKind::Config, "{}", toml);
+        }
+    }
+
+    #[test]
+    fn parses_key_bindings() {
+        let cases = [
+            ("esc", KeyCode::Esc, KeyModifiers::NONE),
+            ("ctrl+q", KeyCode::Char('q'), KeyModifiers::CONTROL),
+            ("Control+Shift+Q", KeyCode::Char('q'), KeyModifiers::CONTROL | KeyModifiers::SHIFT),
+            ("alt+1", KeyCode::Char('1'), KeyModifiers::ALT),
+            ("ctrl++", KeyCode::Char('+'), KeyModifiers::CONTROL),
+            ("+", KeyCode::Char('+'), KeyModifiers::NONE),
+            ("ctrl+space", KeyCode::Char(' '), KeyModifiers::CONTROL),
+            ("f12", KeyCode::F(12), KeyModifiers::NONE),
+        ];
+        for (text, code, modifiers) in cases {
+            assert_eq!(text.parse(), Ok(KeyBinding::new(code, modifiers)), "{}", text);
+        }
+        for text in ["", "ctrl+", "hyper+q", "f13", "ctrl+enterr"] {
+            assert!(text.parse::<KeyBinding>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn shows_key_bindings_the_way_they_are_written() {
+        let cases = [
+            (KeyBinding::new(KeyCode::Char('+'), KeyModifiers::CONTROL), "Ctrl++"),
+            (KeyBinding::new(KeyCode::Char(' '), KeyModifiers::ALT), "Alt+Space"),
+            (KeyBinding::new(KeyCode::PageUp, KeyModifiers::NONE), "PageUp"),
+        ];
+        for (key, shown) in cases {
+            assert_eq!(key.to_string(), shown);
+            assert_eq!(shown.parse(), Ok(key));
+        }
+    }
+}
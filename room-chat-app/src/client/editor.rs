@@ -0,0 +1,444 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Sent lines kept in the history, in memory and on disk.
+const HISTORY_SIZE: usize = 1000;
+
+/// The input box's contents: possibly several lines of text with a cursor
+/// that moves a grapheme cluster at a time, and the history of sent lines.
+pub struct LineEditor {
+    text: String,
+    /// Byte offset of the cursor, always on a grapheme boundary.
+    cursor: usize,
+    history: Vec<String>,
+    /// The history entry shown, `None` while editing a new line.
+    recalled: Option<usize>,
+    /// The new line put aside while going through the history.
+    draft: String,
+    history_file: Option<PathBuf>,
+}
+
+/// Lines that carry a password, which are never written to the history.
+fn is_secret(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("/register") => true,
+        // Room and password
+        Some("/join") => words.nth(1).is_some(),
+        Some("/mode") => line.contains("+k"),
+        _ => false,
+    }
+}
+
+impl LineEditor {
+    /// Creates an empty editor whose history is kept in `history_file`,
+    /// loading what is there already. History is a convenience, so a file
+    /// that cannot be read or written just leaves it in memory.
+    pub fn new(history_file: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().filter_map(|l| serde_json::from_str(l).ok()).collect())
+            .unwrap_or_default();
+        if history.len() > HISTORY_SIZE {
+            history.drain(..history.len() - HISTORY_SIZE);
+            if let Some(path) = &history_file {
+                let _ = rewrite_history(path, &history);
+            }
+        }
+
+        LineEditor {
+            text: String::new(),
+            cursor: 0,
+            history,
+            recalled: None,
+            draft: String::new(),
+            history_file,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Byte offset of the cursor in `text()`.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Puts `text` in place of what lies between `start` and the cursor,
+    /// leaving the cursor after it.
+    pub fn replace_to_cursor(&mut self, start: usize, text: &str) {
+        self.text.replace_range(start..self.cursor, text);
+        self.cursor = start + text.len();
+    }
+
+    /// Replaces the contents, with the cursor at the end.
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = self.text.len();
+        self.recalled = None;
+    }
+
+    /// Empties the editor, recording what it held in the history.
+    pub fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.recalled = None;
+        self.draft.clear();
+
+        if !line.trim().is_empty() && !is_secret(&line) && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+            if self.history.len() > HISTORY_SIZE {
+                self.history.remove(0);
+            }
+            if let Some(path) = &self.history_file {
+                let _ = append_history(path, &line);
+            }
+        }
+        line
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Where the grapheme before the cursor starts.
+    fn prev_boundary(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// Where the grapheme after the cursor ends.
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |g| self.cursor + g.len())
+    }
+
+    /// Where the word before the cursor starts, skipping any whitespace
+    /// right before it.
+    fn prev_word(&self) -> usize {
+        let before = &self.text[..self.cursor];
+        let end = before.trim_end().len();
+        before[..end]
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + before[i..].chars().next().map_or(1, char::len_utf8))
+    }
+
+    /// Where the word after the cursor ends, skipping any whitespace right
+    /// after the cursor.
+    fn next_word(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let start = after.len() - after.trim_start().len();
+        let end = after[start..]
+            .find(char::is_whitespace)
+            .map_or(after.len(), |i| start + i);
+        self.cursor + end
+    }
+
+    /// Where the line the cursor is on starts and ends.
+    fn line_bounds(&self) -> (usize, usize) {
+        let start = self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+        let end = self.text[self.cursor..]
+            .find('\n')
+            .map_or(self.text.len(), |i| self.cursor + i);
+        (start, end)
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.prev_boundary();
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn word_left(&mut self) {
+        self.cursor = self.prev_word();
+    }
+
+    pub fn word_right(&mut self) {
+        self.cursor = self.next_word();
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = self.line_bounds().0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.line_bounds().1;
+    }
+
+    pub fn backspace(&mut self) {
+        let start = self.prev_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    /// Deletes the word before the cursor, like Ctrl+W in a shell.
+    pub fn kill_word(&mut self) {
+        let start = self.prev_word();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// Deletes from the start of the line to the cursor, like Ctrl+U in a
+    /// shell.
+    pub fn kill_to_start(&mut self) {
+        let start = self.line_bounds().0;
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    /// Moves to the line above, or on the first line recalls the previous
+    /// entry of the history.
+    pub fn up(&mut self) {
+        let (start, _) = self.line_bounds();
+        if start > 0 {
+            let column = self.column();
+            self.cursor = start - 1;
+            self.home();
+            self.move_to_column(column);
+            return;
+        }
+        let recalled = match self.recalled {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.text);
+                self.history.len() - 1
+            }
+        };
+        self.recall(Some(recalled));
+    }
+
+    /// Moves to the line below, or on the last line recalls the next entry
+    /// of the history, and after that the line being written before.
+    pub fn down(&mut self) {
+        let (_, end) = self.line_bounds();
+        if end < self.text.len() {
+            let column = self.column();
+            self.cursor = end + 1;
+            self.move_to_column(column);
+            return;
+        }
+        match self.recalled {
+            Some(i) if i + 1 < self.history.len() => self.recall(Some(i + 1)),
+            Some(_) => self.recall(None),
+            None => {}
+        }
+    }
+
+    fn recall(&mut self, entry: Option<usize>) {
+        self.text = match entry {
+            Some(i) => self.history[i].clone(),
+            None => std::mem::take(&mut self.draft),
+        };
+        self.cursor = self.text.len();
+        self.recalled = entry;
+    }
+
+    /// Display width of the current line up to the cursor.
+    fn column(&self) -> usize {
+        let (start, _) = self.line_bounds();
+        self.text[start..self.cursor].width()
+    }
+
+    /// Moves along the current line to the grapheme at display `column`, or
+    /// the end of the line if it is shorter.
+    fn move_to_column(&mut self, column: usize) {
+        let (start, end) = self.line_bounds();
+        let mut width = 0;
+        self.cursor = end;
+        for (i, grapheme) in self.text[start..end].grapheme_indices(true) {
+            if width + grapheme.width() > column {
+                self.cursor = start + i;
+                break;
+            }
+            width += grapheme.width();
+        }
+    }
+
+    /// The cursor's line and display column, counting from 0.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let row = self.text[..self.cursor].matches('\n').count();
+        (row, self.column())
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.text.matches('\n').count() + 1
+    }
+}
+
+fn append_history(path: &PathBuf, line: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // One JSON string per line, so entries may span several lines
+    writeln!(file, "{}", serde_json::to_string(line)?)
+}
+
+fn rewrite_history(path: &PathBuf, history: &[String]) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for line in history {
+        writeln!(file, "{}", serde_json::to_string(line)?)?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editing(text: &str) -> LineEditor {
+        let mut editor = LineEditor::new(None);
+        editor.set_text(text);
+        editor
+    }
+
+    // "e" and a combining acute accent, then a family of three joined by ZWJs
+    const ACCENTED: &str = "e\u{301}";
+    const FAMILY: &str = "\u{1f469}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+
+    #[test]
+    fn moves_over_grapheme_clusters() {
+        let mut editor = editing(&format!("a{}{}", ACCENTED, FAMILY));
+        editor.left();
+        assert_eq!(editor.cursor(), 1 + ACCENTED.len());
+        editor.left();
+        assert_eq!(editor.cursor(), 1);
+        editor.right();
+        assert_eq!(editor.cursor(), 1 + ACCENTED.len());
+        editor.right();
+        assert_eq!(editor.cursor(), editor.text().len());
+        editor.right();
+        assert_eq!(editor.cursor(), editor.text().len());
+    }
+
+    #[test]
+    fn deletes_whole_grapheme_clusters() {
+        let mut editor = editing(&format!("a{}{}", ACCENTED, FAMILY));
+        editor.backspace();
+        assert_eq!(editor.text(), format!("a{}", ACCENTED));
+        editor.backspace();
+        assert_eq!(editor.text(), "a");
+
+        let mut editor = editing(&format!("{}b", FAMILY));
+        editor.home();
+        editor.delete();
+        assert_eq!(editor.text(), "b");
+    }
+
+    #[test]
+    fn keeps_the_display_column_across_wide_characters() {
+        let mut editor = editing("日本語\nabcdef");
+        for _ in 0..3 {
+            editor.left();
+        }
+        assert_eq!(editor.cursor_position(), (1, 3));
+        // Column 3 is the right half of "本", which the cursor lands before
+        editor.up();
+        assert_eq!(editor.cursor(), "日".len());
+        assert_eq!(editor.cursor_position(), (0, 2));
+        editor.down();
+        assert_eq!(editor.cursor_position(), (1, 2));
+        assert_eq!(editor.cursor(), "日本語\nab".len());
+        editor.end();
+        editor.up();
+        assert_eq!(editor.cursor(), "日本語".len());
+    }
+
+    #[test]
+    fn kills_the_word_before_the_cursor() {
+        let mut editor = editing("hello big  world");
+        editor.kill_word();
+        assert_eq!(editor.text(), "hello big  ");
+        editor.kill_word();
+        assert_eq!(editor.text(), "hello ");
+
+        let mut editor = editing("hi\u{3000}there");
+        editor.kill_word();
+        assert_eq!(editor.text(), "hi\u{3000}");
+        assert_eq!(editor.cursor(), editor.text().len());
+    }
+
+    #[test]
+    fn moves_through_lines_before_the_history() {
+        let mut editor = LineEditor::new(None);
+        for line in ["first", "second"] {
+            editor.set_text(line);
+            editor.submit();
+        }
+        editor.set_text("a\nb");
+
+        editor.up();
+        assert_eq!(editor.text(), "a\nb");
+        assert_eq!(editor.cursor_position().0, 0);
+        editor.up();
+        assert_eq!(editor.text(), "second");
+        editor.up();
+        assert_eq!(editor.text(), "first");
+        editor.up();
+        assert_eq!(editor.text(), "first");
+        editor.down();
+        assert_eq!(editor.text(), "second");
+        // Past the newest entry the line being written comes back
+        editor.down();
+        assert_eq!(editor.text(), "a\nb");
+        editor.down();
+        assert_eq!(editor.text(), "a\nb");
+    }
+
+    #[test]
+    fn keeps_passwords_out_of_the_history() {
+        let file = format!("room-chat-history-{}", std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = fs::remove_file(&path);
+        let mut editor = LineEditor::new(Some(path.clone()));
+        let lines = [
+            "/join secret hunter2",
+            "/register hunter2",
+            "/mode secret +k hunter2",
+            "/join lobby",
+            "hello",
+        ];
+        for line in lines {
+            editor.set_text(line);
+            editor.submit();
+        }
+
+        let kept: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(kept, ["/join lobby", "hello"]);
+        // Nor are they recalled after a restart
+        let mut editor = LineEditor::new(Some(path.clone()));
+        editor.up();
+        editor.up();
+        assert_eq!(editor.text(), "/join lobby");
+        editor.up();
+        assert_eq!(editor.text(), "/join lobby");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,38 +1,178 @@
-use crate::common::{ChatError, Message};
+use super::ConnectionStatus;
+use crate::common::codec::{DEFAULT_MAX_FRAME_SIZE, FRAME_OVERHEAD};
+use crate::common::{
+    ChatError, ChatErrorKind, ClientFrame, Command, FrameReader, FrameWriter, ServerFrame,
+    PROTOCOL_VERSION,
+};
+use std::time::{Duration, Instant};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
 
+/// How often the server is pinged, which also keeps the session from being
+/// dropped as idle.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Silence after which the server is shown as not responding.
+const UNRESPONSIVE_AFTER: Duration = Duration::from_secs(20);
+/// Silence after which the server is given up on.
+const DEAD_AFTER: Duration = Duration::from_secs(60);
+
+/// The network side of the client: writes what the UI sends to the server,
+/// and hands what the server sends to the UI.
 pub struct ClientHandler {
-    username: String,
-    tx: mpsc::Sender<Message>,
+    reader: FrameReader<OwnedReadHalf>,
+    writer: FrameWriter<OwnedWriteHalf>,
+    nickname: String,
+    // Frames from the server, for the UI
+    incoming: mpsc::Sender<ServerFrame>,
+    // Frames from the UI, for the server
+    outgoing: mpsc::Receiver<ClientFrame>,
 }
 
 impl ClientHandler {
-    pub fn new(username: String, tx: mpsc::Sender<Message>) -> Self {
-        ClientHandler {
-            username,
-            tx,
+    /// Performs the login handshake on `stream`, proposing `username` as the
+    /// nickname. Fails with the server's reason if the nickname is rejected
+    /// or the password does not match.
+    pub async fn login(
+        stream: TcpStream,
+        username: String,
+        password: Option<String>,
+        incoming: mpsc::Sender<ServerFrame>,
+        outgoing: mpsc::Receiver<ClientFrame>,
+    ) -> Result<Self, ChatError> {
+        let (reader, writer) = stream.into_split();
+        // The server may wrap what it relays in up to `FRAME_OVERHEAD` bytes
+        let mut reader =
+            FrameReader::new(reader).with_max_frame_size(DEFAULT_MAX_FRAME_SIZE + FRAME_OVERHEAD);
+        let mut writer = FrameWriter::new(writer);
+
+        writer
+            .write_frame(&ClientFrame::Hello {
+                version: PROTOCOL_VERSION,
+                nickname: username,
+                password,
+            })
+            .await?;
+
+        match reader.read_frame::<ServerFrame>().await? {
+            Some(ServerFrame::Welcome { version, nickname }) if version == PROTOCOL_VERSION => {
+                Ok(ClientHandler {
+                    reader,
+                    writer,
+                    nickname,
+                    incoming,
+                    outgoing,
+                })
+            }
+            Some(ServerFrame::Welcome { version, .. }) => Err(ChatError {
+                kind: ChatErrorKind::Connection,
+                message: format!(
+                    "Server speaks protocol version {}, expected {}",
+                    version, PROTOCOL_VERSION
+                ),
+            }),
+            Some(ServerFrame::Error { code, message }) => Err(ChatError {
+                kind: code,
+                message,
+            }),
+            Some(_) | None => Err(ChatError {
+                kind: ChatErrorKind::Connection,
+                message: "Server closed the connection during login".to_string(),
+            }),
         }
     }
 
-    pub async fn run(&self, mut stream: TcpStream) -> Result<(), ChatError> {
-        let (mut reader, mut writer) = stream.split();
-        let mut buf = [0u8; 1024];
+    /// The nickname the server knows this session by.
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    /// Asks to join each of `rooms`. The server's replies arrive like any
+    /// other once the handler runs.
+    pub async fn join_rooms(&mut self, rooms: &[String]) -> Result<(), ChatError> {
+        for room in rooms {
+            let join = Command::Join {
+                room: room.clone(),
+                password: None,
+            };
+            self.writer.write_frame(&ClientFrame::Command(join)).await?;
+        }
+        Ok(())
+    }
+
+    /// Passes frames between the server and the UI until the connection
+    /// ends or the UI goes away, keeping `status` up to date along the way.
+    pub async fn run(mut self, status: watch::Sender<ConnectionStatus>) -> Result<(), ChatError> {
+        let result = self.serve(&status).await;
+        let reason = match &result {
+            Ok(()) => "Server closed the connection".to_string(),
+            Err(e) => e.message.clone(),
+        };
+        status.send_replace(ConnectionStatus::Disconnected(reason));
+        result
+    }
+
+    async fn serve(&mut self, status: &watch::Sender<ConnectionStatus>) -> Result<(), ChatError> {
+        let mut last_heard = Instant::now();
+        let mut ping = tokio::time::interval(PING_INTERVAL);
 
         loop {
-            match reader.read(&mut buf).await {
-                Ok(0) => break, // Connection closed
-                Ok(n) => {
-                    let msg: Message = serde_json::from_slice(&buf[..n])?;
-                    self.tx.send(msg).await.map_err(|e| ChatError {
-                        kind: crate::common::ChatErrorKind::Message,
-                        message: e.to_string(),
-                    })?;
+            tokio::select! {
+                frame = self.reader.read_frame::<ServerFrame>() => {
+                    let frame = match frame {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => return Ok(()),
+                        Err(e) if e.is_fatal() => return Err(e),
+                        // The bad frame was skipped, the UI shows why
+                        Err(e) => ServerFrame::from(e),
+                    };
+                    last_heard = Instant::now();
+                    status.send_if_modified(|status| {
+                        let changed = *status != ConnectionStatus::Connected;
+                        *status = ConnectionStatus::Connected;
+                        changed
+                    });
+
+                    match frame {
+                        ServerFrame::Ping => self.writer.write_frame(&ClientFrame::Pong).await?,
+                        ServerFrame::Pong => {}
+                        frame => {
+                            if self.incoming.send(frame).await.is_err() {
+                                // The UI is gone
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+                frame = self.outgoing.recv() => {
+                    let Some(frame) = frame else {
+                        // The UI is gone
+                        return Ok(());
+                    };
+                    match self.writer.write_frame(&frame).await {
+                        // Nothing was sent, only that frame is lost
+                        Err(e) if e.kind == ChatErrorKind::Protocol => {
+                            if self.incoming.send(ServerFrame::from(e)).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        result => result?,
+                    }
+                }
+                _ = ping.tick() => {
+                    let silent = last_heard.elapsed();
+                    if silent >= DEAD_AFTER {
+                        return Err(ChatError {
+                            kind: ChatErrorKind::Connection,
+                            message: format!("No answer from the server for {}s", silent.as_secs()),
+                        });
+                    }
+                    if silent >= UNRESPONSIVE_AFTER {
+                        status.send_replace(ConnectionStatus::Unresponsive(silent));
+                    }
+                    self.writer.write_frame(&ClientFrame::Ping).await?;
                 }
-                Err(e) => return Err(e.into()),
             }
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
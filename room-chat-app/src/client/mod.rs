@@ -1,43 +1,88 @@
 use tokio::net::TcpStream;
-use crate::common::ChatError;
-use tokio::sync::mpsc;
+use crate::common::{ChatError, ChatErrorKind};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 
+pub mod complete;
+pub mod config;
+pub mod editor;
 pub mod handler;
 pub mod ui;
 
+pub use config::ClientConfig;
+
+/// Frames buffered between the connection and the UI in either direction.
+const CHANNEL_CAPACITY: usize = 100;
+/// How long the connection gets to say goodbye once the UI has closed.
+const QUIT_GRACE: Duration = Duration::from_secs(1);
+
+/// The state of the connection to the server, shown in the status bar.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// Nothing heard from the server for this long.
+    Unresponsive(Duration),
+    /// The connection is gone, and why.
+    Disconnected(String),
+}
+
 pub struct ChatClient {
     stream: TcpStream,
-    username: String,
+    config: ClientConfig,
 }
 
 impl ChatClient {
-    pub async fn new(address: &str, username: String) -> Result<Self, ChatError> {
-        let stream = TcpStream::connect(address).await?;
-        
-        Ok(ChatClient { 
-            stream,
-            username,
-        })
+    /// Connects to the server named in `config`.
+    pub async fn new(config: ClientConfig) -> Result<Self, ChatError> {
+        let address = (config.host.as_str(), config.port);
+        let stream = TcpStream::connect(address).await.map_err(|e| ChatError {
+            kind: ChatErrorKind::Connection,
+            message: format!("Cannot connect to {}:{}: {}", config.host, config.port, e),
+        })?;
+
+        Ok(ChatClient { stream, config })
     }
 
-    pub async fn run(&mut self) -> Result<(), ChatError> {
-        let (tx, _rx) = mpsc::channel(100);
-        
-        // Create a new connection for the handler
-        let handler_stream = TcpStream::connect(self.stream.peer_addr()?).await?;
-        
-        let handler = handler::ClientHandler::new(
-            self.username.clone(),
-            tx.clone(),
+    /// Logs in and runs the UI until the user quits. The connection is split
+    /// between a task talking to the server and the UI, which trade frames
+    /// over a pair of channels.
+    pub async fn run(self) -> Result<(), ChatError> {
+        let (incoming_tx, incoming_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+        let mut handler = handler::ClientHandler::login(
+            self.stream,
+            self.config.nickname().to_string(),
+            self.config.password.clone(),
+            incoming_tx,
+            outgoing_rx,
+        )
+        .await?;
+        handler.join_rooms(&self.config.auto_join).await?;
+        let nickname = handler.nickname().to_string();
+
+        // The UI stays up after the connection ends so the status bar can say
+        // why, until the user leaves
+        let (status_tx, status_rx) = watch::channel(ConnectionStatus::Connected);
+        let mut connection = tokio::spawn(handler.run(status_tx));
+
+        let mut ui = ui::UI::new(
+            nickname,
+            outgoing_tx,
+            incoming_rx,
+            status_rx,
+            self.config.theme,
+            self.config.keys.clone(),
+            self.config.history_file.clone(),
         );
-        
-        let mut ui = ui::UI::new(tx);
-        
-        tokio::select! {
-            result = handler.run(handler_stream) => result?,
-            result = ui.run() => result?,
-        }
+        let result = ui.run().await;
 
-        Ok(())
+        // Dropping the UI closes its channel, letting the connection send
+        // whatever it was left with and finish
+        drop(ui);
+        if tokio::time::timeout(QUIT_GRACE, &mut connection).await.is_err() {
+            connection.abort();
+        }
+        result
     }
-}
\ No newline at end of file
+}
@@ -1,49 +1,334 @@
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use crossterm::event::{
+    KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::terminal::supports_keyboard_enhancement;
+use std::collections::{HashSet, VecDeque};
 use std::io;
+use std::path::PathBuf;
 use std::time::Duration;
 use tui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Terminal,
 };
-use tokio::sync::mpsc;
-use crate::common::{ChatError, Message};
+use tokio::sync::{mpsc, watch};
+use unicode_width::UnicodeWidthStr;
+use super::complete::Completion;
+use super::config::{KeyBindings, Theme};
+use super::editor::LineEditor;
+use super::ConnectionStatus;
+use crate::common::protocol::same_room;
+use crate::common::{ChatError, ClientFrame, Command, Message, Reply, RoomEvent, ServerFrame};
+
+/// Width of the room and member panes on either side of the messages.
+const SIDEBAR_WIDTH: u16 = 20;
+/// Lines of a multi-line input shown at once before it scrolls.
+const MAX_INPUT_LINES: usize = 5;
+/// Messages kept in each room's buffer, and in the notices shown before any
+/// room is. The oldest go first.
+const MAX_BUFFERED: usize = 2000;
+
+/// The colours of each part of the UI under a `Theme`.
+struct Palette {
+    room: Color,
+    text: Color,
+    unread: Color,
+    connected: Color,
+    unresponsive: Color,
+    disconnected: Color,
+}
+
+impl Palette {
+    fn new(theme: Theme) -> Self {
+        match theme {
+            Theme::Dark => Palette {
+                room: Color::Yellow,
+                text: Color::White,
+                unread: Color::Cyan,
+                connected: Color::Green,
+                unresponsive: Color::Yellow,
+                disconnected: Color::Red,
+            },
+            Theme::Light => Palette {
+                room: Color::Blue,
+                text: Color::Black,
+                unread: Color::Magenta,
+                connected: Color::Green,
+                unresponsive: Color::Magenta,
+                disconnected: Color::Red,
+            },
+            Theme::Mono => Palette {
+                room: Color::Reset,
+                text: Color::Reset,
+                unread: Color::Reset,
+                connected: Color::Reset,
+                unresponsive: Color::Reset,
+                disconnected: Color::Reset,
+            },
+        }
+    }
+}
+
+/// A joined room: its own message buffer, and what the sidebar and member
+/// list show of it.
+struct RoomView {
+    name: String,
+    messages: VecDeque<Message>,
+    /// Chat messages that arrived while another room was shown.
+    unread: usize,
+    /// How far the user had read when they last looked away: messages from
+    /// this index on are new to them.
+    read_marker: usize,
+    /// Lines scrolled back from the newest message, 0 to follow new ones.
+    scroll: usize,
+    /// Messages that arrived while scrolled back.
+    below: usize,
+    members: Vec<String>,
+}
+
+impl RoomView {
+    fn new(name: String) -> Self {
+        RoomView {
+            name,
+            messages: VecDeque::new(),
+            unread: 0,
+            read_marker: 0,
+            scroll: 0,
+            below: 0,
+            members: Vec::new(),
+        }
+    }
+
+    /// Whether the read marker is drawn, between the messages before and
+    /// from it.
+    fn shows_read_marker(&self) -> bool {
+        self.read_marker > 0 && self.read_marker < self.messages.len()
+    }
+
+    /// Lines in the message pane, the read marker included.
+    fn line_count(&self) -> usize {
+        self.line_of(self.messages.len())
+    }
+
+    /// How far back a pane `page` lines high can scroll. Scrolled back, a
+    /// line of it goes to the indicator of what is below.
+    fn max_scroll(&self, page: usize) -> usize {
+        match self.line_count() {
+            lines if lines > page => lines - page.saturating_sub(1),
+            _ => 0,
+        }
+    }
+
+    /// The line message `index` starts on.
+    fn line_of(&self, index: usize) -> usize {
+        let lines: usize = self.messages.range(..index).map(line_count).sum();
+        lines + (self.shows_read_marker() && index >= self.read_marker) as usize
+    }
+
+    /// Indices of the messages containing `term`, oldest first.
+    fn matches(&self, term: &str) -> Vec<usize> {
+        self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| contains_term(&m.content, term))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The oldest message in the buffer that is part of the room's history,
+    /// which `/history` pages back from. Notices are not, nor are private
+    /// messages shown here.
+    fn oldest(&self) -> Option<u64> {
+        self.messages
+            .iter()
+            .find(|m| m.sender != "System" && same_room(&m.room, &self.name))
+            .map(|m| m.id)
+    }
+
+    /// Adds `message` at the bottom, returning the oldest message if it had
+    /// to go to make room.
+    fn push(&mut self, message: Message) -> Option<Message> {
+        // Stay put while the user is reading further back
+        if self.scroll > 0 {
+            self.scroll += line_count(&message);
+            self.below += 1;
+        }
+        self.messages.push_back(message);
+        if self.messages.len() <= MAX_BUFFERED {
+            return None;
+        }
+        self.read_marker = self.read_marker.saturating_sub(1);
+        self.messages.pop_front()
+    }
+
+    /// Puts a page of older history, oldest first, above the messages
+    /// already in the buffer, as much of it as fits. Returns how many
+    /// messages were added.
+    fn prepend(&mut self, messages: &[Message]) -> usize {
+        let space = MAX_BUFFERED.saturating_sub(self.messages.len());
+        let page = &messages[messages.len().saturating_sub(space)..];
+        if self.read_marker > 0 {
+            self.read_marker += page.len();
+        }
+        for message in page.iter().rev() {
+            self.messages.push_front(message.clone());
+        }
+        page.len()
+    }
+
+    fn add_member(&mut self, user: &str) {
+        if let Err(at) = self.members.binary_search_by(|m| m.as_str().cmp(user)) {
+            self.members.insert(at, user.to_string());
+        }
+    }
+
+    fn remove_member(&mut self, user: &str) {
+        self.members.retain(|m| m != user);
+    }
+}
+
+/// Lines `message` takes in the message pane.
+fn line_count(message: &Message) -> usize {
+    message.content.split('\n').count()
+}
+
+/// Whether `content` contains `term`, ignoring ASCII case.
+fn contains_term(content: &str, term: &str) -> bool {
+    content.to_ascii_lowercase().contains(&term.to_ascii_lowercase())
+}
+
+/// What `/find` last looked for in the room shown.
+struct Search {
+    term: String,
+    /// The message jumped to, `None` if nothing matched.
+    at: Option<usize>,
+    /// How many messages in the buffer match, and how many of those are at
+    /// or before `at`. Kept up to date as messages come and go, so the
+    /// status bar need not search the buffer on every redraw.
+    matches: usize,
+    nth: usize,
+}
+
+/// Splits `content` into spans, picking out each occurrence of `term`
+/// (ignoring ASCII case) in `highlight`.
+fn highlighted<'a>(content: &'a str, term: &str, normal: Style, highlight: Style) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    if term.is_empty() {
+        spans.push(Span::styled(content, normal));
+        return spans;
+    }
+    // ASCII lowercasing keeps byte offsets, so they apply to `content` too
+    let haystack = content.to_ascii_lowercase();
+    let term = term.to_ascii_lowercase();
+    let mut start = 0;
+    while let Some(found) = haystack[start..].find(&term) {
+        let at = start + found;
+        spans.push(Span::styled(&content[start..at], normal));
+        spans.push(Span::styled(&content[at..at + term.len()], highlight));
+        start = at + term.len();
+    }
+    spans.push(Span::styled(&content[start..], normal));
+    spans
+}
 
 pub struct UI {
-    messages: Vec<Message>,
-    input: String,
+    input: LineEditor,
+    /// Joined rooms, in the order they were joined.
+    rooms: Vec<RoomView>,
     current_room: String,
-    tx: mpsc::Sender<Message>,
+    // Notices that arrived before any room was shown
+    notices: VecDeque<Message>,
+    // Rooms whose member list was asked for by the UI, not the user
+    listing: HashSet<String>,
+    // Room the user last asked to join, to be shown once joined
+    joining: Option<String>,
+    search: Option<Search>,
+    // Candidates offered for the word being completed
+    completion: Option<Completion>,
+    // Rooms in the last `/list` reply
+    listed_rooms: Vec<String>,
+    // Lines the message pane showed last time it was drawn
+    page_height: usize,
+    nickname: String,
+    // Frames for the server
+    tx: mpsc::Sender<ClientFrame>,
+    // Frames from the server
+    rx: mpsc::Receiver<ServerFrame>,
+    status: watch::Receiver<ConnectionStatus>,
+    palette: Palette,
+    keys: KeyBindings,
 }
 
 impl UI {
-    pub fn new(tx: mpsc::Sender<Message>) -> Self {
+    /// Creates the UI for `nickname`'s session, sending what the user types
+    /// through `tx` and showing what arrives on `rx`.
+    pub fn new(
+        nickname: String,
+        tx: mpsc::Sender<ClientFrame>,
+        rx: mpsc::Receiver<ServerFrame>,
+        status: watch::Receiver<ConnectionStatus>,
+        theme: Theme,
+        keys: KeyBindings,
+        history_file: Option<PathBuf>,
+    ) -> Self {
         UI {
-            messages: Vec::new(),
-            input: String::new(),
-            current_room: "lobby".to_string(),
+            input: LineEditor::new(history_file),
+            rooms: Vec::new(),
+            current_room: String::new(),
+            notices: VecDeque::new(),
+            listing: HashSet::new(),
+            joining: None,
+            search: None,
+            completion: None,
+            listed_rooms: Vec::new(),
+            page_height: 0,
+            nickname,
             tx,
+            rx,
+            status,
+            palette: Palette::new(theme),
+            keys,
         }
     }
 
     pub async fn run(&mut self) -> Result<(), ChatError> {
+        // A panic would otherwise leave the terminal unusable
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let _ = disable_raw_mode();
+            let _ = execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture);
+            default_hook(info);
+        }));
+
         // Terminal initialization
         enable_raw_mode()?;
         let mut stdout = io::stdout();
         execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        // Lets Shift+Enter be told apart from Enter where the terminal can
+        let enhanced = supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES;
+            execute!(stdout, PushKeyboardEnhancementFlags(flags))?;
+        }
         let backend = CrosstermBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
 
         let result = self.run_app(&mut terminal).await;
 
         // Restore terminal
+        if enhanced {
+            execute!(terminal.backend_mut(), PopKeyboardEnhancementFlags)?;
+        }
         disable_raw_mode()?;
         execute!(
             terminal.backend_mut(),
@@ -60,79 +345,215 @@ impl UI {
         terminal: &mut Terminal<B>,
     ) -> Result<(), ChatError> {
         loop {
+            while let Ok(frame) = self.rx.try_recv() {
+                self.handle_frame(frame);
+            }
+
+            let mut page_height = self.page_height;
+            let input_lines = self.input.line_count().min(MAX_INPUT_LINES);
+            let input_height = input_lines as u16 + 2;
             terminal.draw(|f| {
                 let chunks = Layout::default()
                     .direction(Direction::Vertical)
                     .margin(2)
                     .constraints(
                         [
-                            Constraint::Length(1),  // Room name
-                            Constraint::Min(1),     // Messages
-                            Constraint::Length(3),  // Input
+                            Constraint::Min(1),     // Rooms, messages and members
+                            Constraint::Length(input_height), // Input
+                            Constraint::Length(1),  // Status bar
                         ]
                         .as_ref(),
                     )
                     .split(f.size());
+                let columns = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints(
+                        [
+                            Constraint::Length(SIDEBAR_WIDTH),
+                            Constraint::Min(1),
+                            Constraint::Length(SIDEBAR_WIDTH),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(chunks[0]);
 
-                // Room name
-                let room_name = Paragraph::new(format!("Room: {}", self.current_room))
-                    .style(Style::default().fg(Color::Yellow));
-                f.render_widget(room_name, chunks[0]);
-
-                // Messages
-                let messages: Vec<ListItem> = self
-                    .messages
+                // Rooms
+                let rooms: Vec<ListItem> = self
+                    .rooms
                     .iter()
-                    .map(|m| {
-                        let time = format!("{:02}:{:02}", 
-                            m.timestamp.elapsed().unwrap().as_secs() / 3600,
-                            (m.timestamp.elapsed().unwrap().as_secs() % 3600) / 60);
-                        let content = format!("{} | {} > {}", time, m.sender, m.content);
-                        ListItem::new(vec![Spans::from(vec![
-                            Span::styled(content, Style::default().fg(Color::White)),
-                        ])])
+                    .enumerate()
+                    .map(|(i, room)| {
+                        // Numbered for Alt+number
+                        let label = format!("{} {}", i + 1, room.name);
+                        let (label, style) = if same_room(&room.name, &self.current_room) {
+                            let style = Style::default().fg(self.palette.room);
+                            (label, style.add_modifier(Modifier::BOLD))
+                        } else if room.unread > 0 {
+                            let label = format!("{} ({})", label, room.unread);
+                            (label, Style::default().fg(self.palette.unread))
+                        } else {
+                            (label, Style::default().fg(self.palette.text))
+                        };
+                        ListItem::new(Span::styled(label, style))
                     })
                     .collect();
+                let rooms =
+                    List::new(rooms).block(Block::default().borders(Borders::ALL).title("Rooms"));
+                f.render_widget(rooms, columns[0]);
 
+                // The current room's buffer
+                let view = self.room_view(&self.current_room);
+                let (buffer, scroll, below) = match view {
+                    Some(view) => (&view.messages, view.scroll, view.below),
+                    None => (&self.notices, 0, 0),
+                };
+                // The lines that fit, ending `scroll` lines back from the
+                // newest, less one for the indicator when scrolled back
+                page_height = columns[1].height.saturating_sub(2) as usize;
+                let height = page_height.saturating_sub((scroll > 0) as usize);
+                let (term, at) = match &self.search {
+                    Some(search) => (search.term.as_str(), search.at),
+                    None => ("", None),
+                };
+                let normal = Style::default().fg(self.palette.text);
+                let read_marker = view
+                    .filter(|view| view.shows_read_marker())
+                    .map(|view| view.read_marker);
+                // Only the lines shown are built, working up from the newest
+                let mut skip = scroll;
+                let mut messages: Vec<ListItem> = Vec::new();
+                for (i, m) in buffer.iter().enumerate().rev() {
+                    if messages.len() >= height {
+                        break;
+                    }
+                    let lines = line_count(m) + (read_marker == Some(i)) as usize;
+                    if skip >= lines {
+                        skip -= lines;
+                        continue;
+                    }
+                    let mut items = Vec::new();
+                    if read_marker == Some(i) {
+                        let marker = Span::styled(
+                            "-- new messages --",
+                            Style::default().fg(self.palette.unread),
+                        );
+                        items.push(ListItem::new(marker));
+                    }
+                    // The server's clock may be a little ahead of ours
+                    let age = m.timestamp.elapsed().unwrap_or_default().as_secs();
+                    let time = format!("{:02}:{:02}", age / 3600, (age % 3600) / 60);
+                    let highlight = if at == Some(i) {
+                        normal.add_modifier(Modifier::REVERSED)
+                    } else {
+                        Style::default().fg(self.palette.unread).add_modifier(Modifier::BOLD)
+                    };
+                    // Further lines of the message line up under the first
+                    let prefix = format!("{} | {} > ", time, m.sender);
+                    let indent = " ".repeat(prefix.width());
+                    let mut prefix = Some(prefix);
+                    for line in m.content.split('\n') {
+                        let lead = prefix.take().unwrap_or_else(|| indent.clone());
+                        let mut spans = vec![Span::styled(lead, normal)];
+                        spans.extend(highlighted(line, term, normal, highlight));
+                        items.push(ListItem::new(Spans::from(spans)));
+                    }
+                    let room_left = height - messages.len();
+                    messages.extend(items.into_iter().rev().skip(skip).take(room_left));
+                    skip = 0;
+                }
+                messages.reverse();
+
+                let title = match self.current_room.as_str() {
+                    "" => "Messages",
+                    room => room,
+                };
+                let title = Span::styled(title, Style::default().fg(self.palette.room));
                 let messages = List::new(messages)
-                    .block(Block::default().borders(Borders::ALL).title("Messages"))
-                    .style(Style::default().fg(Color::White));
-                f.render_widget(messages, chunks[1]);
+                    .block(Block::default().borders(Borders::ALL).title(title))
+                    .style(Style::default().fg(self.palette.text));
+                f.render_widget(messages, columns[1]);
 
-                // Input
-                let input = Paragraph::new(self.input.as_ref())
+                if scroll > 0 && page_height > 0 {
+                    let end = self.keys.scroll_bottom;
+                    let indicator = match below {
+                        0 => format!("-- more below ({}) --", end),
+                        n => format!("-- {} new messages below ({}) --", n, end),
+                    };
+                    let area = Rect {
+                        x: columns[1].x + 1,
+                        y: columns[1].y + columns[1].height - 2,
+                        width: columns[1].width.saturating_sub(2),
+                        height: 1,
+                    };
+                    let indicator = Paragraph::new(indicator)
+                        .style(Style::default().fg(self.palette.unread));
+                    f.render_widget(indicator, area);
+                }
+
+                // Members of the current room
+                let members: Vec<ListItem> = self
+                    .room_view(&self.current_room)
+                    .map(|room| room.members.iter().map(|m| ListItem::new(m.as_str())).collect())
+                    .unwrap_or_default();
+                let members = List::new(members)
+                    .block(Block::default().borders(Borders::ALL).title("Members"))
+                    .style(Style::default().fg(self.palette.text));
+                f.render_widget(members, columns[2]);
+
+                // Input, scrolled to keep the cursor in sight
+                let (row, column) = self.input.cursor_position();
+                // At least a column, however narrow the terminal
+                let width = chunks[1].width.saturating_sub(2).max(1) as usize;
+                let scroll_y = (row + 1).saturating_sub(input_lines);
+                let scroll_x = (column + 1).saturating_sub(width);
+                let input = Paragraph::new(self.input.text())
                     .style(Style::default())
-                    .block(Block::default().borders(Borders::ALL).title("Input"));
-                f.render_widget(input, chunks[2]);
+                    .block(Block::default().borders(Borders::ALL).title("Input"))
+                    .scroll((scroll_y as u16, scroll_x as u16));
+                f.render_widget(input, chunks[1]);
+                f.set_cursor(
+                    chunks[1].x + 1 + (column - scroll_x) as u16,
+                    chunks[1].y + 1 + (row - scroll_y) as u16,
+                );
+
+                // Status bar
+                let (status, color) = match &*self.status.borrow() {
+                    ConnectionStatus::Connected => {
+                        ("Connected".to_string(), self.palette.connected)
+                    }
+                    ConnectionStatus::Unresponsive(silent) => (
+                        format!("Server not responding for {}s", silent.as_secs()),
+                        self.palette.unresponsive,
+                    ),
+                    ConnectionStatus::Disconnected(reason) => (
+                        format!("Disconnected: {} ({} to quit)", reason, self.keys.quit),
+                        self.palette.disconnected,
+                    ),
+                };
+                let status = match (&self.search, view) {
+                    (Some(search), Some(_)) => match search.at {
+                        Some(_) => format!(
+                            "{} | find \"{}\": {} of {} ({} for older)",
+                            status,
+                            search.term,
+                            search.nth,
+                            search.matches,
+                            self.keys.find
+                        ),
+                        None => format!("{} | find \"{}\": no matches", status, search.term),
+                    },
+                    _ => status,
+                };
+                let status = Paragraph::new(status).style(Style::default().fg(color));
+                f.render_widget(status, chunks[2]);
             })?;
 
+            self.page_height = page_height;
+
             if event::poll(Duration::from_millis(100))? {
                 if let Event::Key(key) = event::read()? {
-                    match key.code {
-                        KeyCode::Enter => {
-                            if !self.input.is_empty() {
-                                let msg = Message::new(
-                                    self.current_room.clone(),
-                                    "you".to_string(),
-                                    self.input.clone(),
-                                );
-                                self.tx.send(msg).await.map_err(|e| ChatError {
-                                    kind: crate::common::ChatErrorKind::Message,
-                                    message: e.to_string(),
-                                })?;
-                                self.input.clear();
-                            }
-                        }
-                        KeyCode::Char(c) => {
-                            self.input.push(c);
-                        }
-                        KeyCode::Backspace => {
-                            self.input.pop();
-                        }
-                        KeyCode::Esc => {
-                            break;
-                        }
-                        _ => {}
+                    if !self.handle_key(key).await {
+                        break;
                     }
                 }
             }
@@ -140,11 +561,519 @@ impl UI {
         Ok(())
     }
 
-    pub fn add_message(&mut self, msg: Message) {
-        self.messages.push(msg);
+    /// Acts on a key press. Returns false once the user has quit.
+    async fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if self.keys.complete.matches(&key) {
+            self.complete(1);
+            return true;
+        }
+        if key.code == KeyCode::BackTab {
+            self.complete(-1);
+            return true;
+        }
+        // Any other key accepts the candidate shown
+        self.completion = None;
+        if self.keys.quit.matches(&key) {
+            // Nothing to tell a server that is already gone
+            let _ = self.tx.try_send(ClientFrame::Command(Command::Quit(None)));
+            return false;
+        }
+        if let (KeyCode::Char(c @ '1'..='9'), KeyModifiers::ALT) = (key.code, key.modifiers) {
+            let index = c as usize - '1' as usize;
+            if let Some(room) = self.rooms.get(index) {
+                self.set_room(room.name.clone());
+            }
+            return true;
+        }
+        let page = self.page_height.max(1);
+        if self.keys.next_room.matches(&key) {
+            self.cycle_room(1);
+        } else if self.keys.prev_room.matches(&key) {
+            self.cycle_room(-1);
+        } else if self.keys.scroll_up.matches(&key) {
+            self.scroll_to(|scroll| scroll.saturating_add(page));
+        } else if self.keys.scroll_down.matches(&key) {
+            self.scroll_to(|scroll| scroll.saturating_sub(page));
+        } else if self.keys.scroll_top.matches(&key) {
+            self.scroll_to(|_| usize::MAX);
+        } else if self.keys.scroll_bottom.matches(&key) {
+            self.scroll_to(|_| 0);
+        } else if self.keys.find.matches(&key) {
+            match &self.search {
+                Some(search) => {
+                    let term = search.term.clone();
+                    self.find(&term, search.at);
+                }
+                None => self.input.set_text("/find "),
+            }
+        } else if self.keys.send.matches(&key) {
+            return self.submit().await;
+        } else if self.keys.newline.matches(&key) {
+            self.input.insert('\n');
+        } else {
+            self.edit(key);
+        }
+        true
+    }
+
+    /// Applies an editing key to the input, the way a shell's line editor
+    /// would.
+    fn edit(&mut self, key: KeyEvent) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            KeyCode::Char('w') if ctrl && !alt => self.input.kill_word(),
+            KeyCode::Char('u') if ctrl && !alt => self.input.kill_to_start(),
+            KeyCode::Char('a') if ctrl && !alt => self.input.home(),
+            KeyCode::Char('e') if ctrl && !alt => self.input.end(),
+            KeyCode::Char('b') if alt && !ctrl => self.input.word_left(),
+            KeyCode::Char('f') if alt && !ctrl => self.input.word_right(),
+            // AltGr arrives as Ctrl+Alt on some terminals
+            KeyCode::Char(c) if ctrl == alt => self.input.insert(c),
+            KeyCode::Left if ctrl => self.input.word_left(),
+            KeyCode::Right if ctrl => self.input.word_right(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.up(),
+            KeyCode::Down => self.input.down(),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            _ => {}
+        }
     }
 
+    /// Completes the word before the cursor, or puts the candidate `step`
+    /// on from the one shown in its place.
+    fn complete(&mut self, step: isize) {
+        match &mut self.completion {
+            Some(completion) => completion.cycle(step),
+            None => {
+                let mut rooms = self.listed_rooms.clone();
+                rooms.extend(self.rooms.iter().map(|room| room.name.clone()));
+                let nicknames = self
+                    .room_view(&self.current_room)
+                    .map(|room| room.members.as_slice())
+                    .unwrap_or_default();
+                let text = self.input.text();
+                let completion = Completion::new(text, self.input.cursor(), &rooms, nicknames);
+                let Some(mut completion) = completion else {
+                    return;
+                };
+                // Shift+Tab starts from the last candidate
+                if step < 0 {
+                    completion.cycle(step);
+                }
+                self.completion = Some(completion);
+            }
+        }
+        if let Some(completion) = &self.completion {
+            self.input.replace_to_cursor(completion.start(), completion.current());
+        }
+    }
+
+    /// Sends the input line, or acts on it if it is meant for the client
+    /// itself. Returns false if it quit.
+    async fn submit(&mut self) -> bool {
+        if self.input.is_empty() {
+            return true;
+        }
+        if let Some(term) = self.input.text().strip_prefix("/find") {
+            if term.is_empty() || term.starts_with(' ') {
+                let term = term.trim().to_string();
+                self.input.submit();
+                if term.is_empty() {
+                    self.search = None;
+                } else {
+                    self.find(&term, None);
+                }
+                return true;
+            }
+        }
+
+        let frame =
+            ClientFrame::from_input(&self.current_room, &self.nickname, self.input.text());
+        match frame {
+            Ok(mut frame) => {
+                // Message ids are not shown, so paging starts from the
+                // oldest message the room has in its buffer
+                if let ClientFrame::Command(Command::History { room, before, .. }) = &mut frame {
+                    *before = self.room_view(room).and_then(RoomView::oldest);
+                }
+                let quit = matches!(frame, ClientFrame::Command(Command::Quit(_)));
+                if let ClientFrame::Command(Command::Join { room, .. }) = &frame {
+                    self.joining = Some(room.clone());
+                }
+                // A lost connection already shows in the status bar
+                let _ = self.tx.send(frame).await;
+                self.input.submit();
+                !quit
+            }
+            // Left in the input to be corrected
+            Err(e) => {
+                self.handle_frame(ServerFrame::from(e));
+                true
+            }
+        }
+    }
+
+    /// Scrolls the room shown to `to(scroll)` lines back from the newest,
+    /// as far as there is anything to show.
+    fn scroll_to(&mut self, to: impl FnOnce(usize) -> usize) {
+        let page = self.page_height;
+        let current = self.current_room.clone();
+        if let Some(view) = self.room_view_mut(&current) {
+            view.scroll = to(view.scroll).min(view.max_scroll(page));
+            if view.scroll == 0 {
+                view.below = 0;
+            }
+        }
+    }
+
+    /// Looks for `term` in the room shown, case-insensitively, and scrolls to
+    /// the newest match older than message `before`, wrapping around to the
+    /// newest match overall.
+    fn find(&mut self, term: &str, before: Option<usize>) {
+        let page = self.page_height;
+        let current = self.current_room.clone();
+        let Some(view) = self.room_view_mut(&current) else {
+            return;
+        };
+        let matches = view.matches(term);
+        let at = matches
+            .iter()
+            .rev()
+            .find(|&&i| before.is_none_or(|before| i < before))
+            .or(matches.last())
+            .copied();
+        if let Some(at) = at {
+            // Bring the match to the middle of the pane
+            let line = view.line_of(at);
+            let scroll = (view.line_count() - 1 - line).saturating_sub(page / 2);
+            view.scroll = scroll.min(view.max_scroll(page));
+            if view.scroll == 0 {
+                view.below = 0;
+            }
+        }
+        self.search = Some(Search {
+            term: term.to_string(),
+            at,
+            matches: matches.len(),
+            nth: matches.iter().filter(|&&i| Some(i) <= at).count(),
+        });
+    }
+
+    fn room_view(&self, name: &str) -> Option<&RoomView> {
+        self.rooms.iter().find(|room| same_room(&room.name, name))
+    }
+
+    fn room_view_mut(&mut self, name: &str) -> Option<&mut RoomView> {
+        self.rooms.iter_mut().find(|room| same_room(&room.name, name))
+    }
+
+    /// Shows `room`, marking what arrived in it as read. The room shown so
+    /// far remembers where the user stopped reading.
     pub fn set_room(&mut self, room: String) {
+        if same_room(&room, &self.current_room) {
+            return;
+        }
+        let current = self.current_room.clone();
+        if let Some(view) = self.room_view_mut(&current) {
+            view.read_marker = view.messages.len();
+        }
+        if let Some(view) = self.room_view_mut(&room) {
+            view.unread = 0;
+        }
         self.current_room = room;
+        self.search = None;
+    }
+
+    /// Shows the joined room `step` places after the current one, wrapping
+    /// around.
+    fn cycle_room(&mut self, step: isize) {
+        if self.rooms.is_empty() {
+            return;
+        }
+        let count = self.rooms.len() as isize;
+        let next = match self.rooms.iter().position(|r| same_room(&r.name, &self.current_room)) {
+            Some(at) => (at as isize + step).rem_euclid(count),
+            None => 0,
+        };
+        self.set_room(self.rooms[next as usize].name.clone());
+    }
+
+    fn remove_room(&mut self, name: &str) {
+        self.rooms.retain(|room| !same_room(&room.name, name));
+        if same_room(&self.current_room, name) {
+            if let Some(room) = self.rooms.first() {
+                self.set_room(room.name.clone());
+            }
+        }
+    }
+
+    fn rename_room(&mut self, name: &str, new_name: &str) {
+        if let Some(view) = self.room_view_mut(name) {
+            view.name = new_name.to_string();
+        }
+        if same_room(&self.current_room, name) {
+            self.current_room = new_name.to_string();
+        }
+    }
+
+    /// Updates the UI with a frame from the server.
+    fn handle_frame(&mut self, frame: ServerFrame) {
+        match &frame {
+            ServerFrame::Reply(Reply::Joined { room, .. }) => {
+                if self.room_view(room).is_none() {
+                    let mut view = RoomView::new(room.clone());
+                    if self.rooms.is_empty() {
+                        // Nowhere else to see them from now on
+                        view.messages = std::mem::take(&mut self.notices);
+                    }
+                    self.rooms.push(view);
+                }
+                // Rooms joined on the user's say-so are shown straight away,
+                // others only if nothing joined is shown yet
+                let asked = self.joining.as_ref().is_some_and(|joining| same_room(joining, room));
+                if asked || self.room_view(&self.current_room).is_none() {
+                    self.joining = None;
+                    self.set_room(room.clone());
+                }
+                // Fill in the member list
+                let list = ClientFrame::Command(Command::ListUsers(room.clone()));
+                if self.tx.try_send(list).is_ok() {
+                    self.listing.insert(room.clone());
+                }
+            }
+            ServerFrame::Reply(Reply::Users { room, users }) => {
+                if let Some(view) = self.room_view_mut(room) {
+                    view.members = users.clone();
+                    view.members.sort();
+                }
+                if self.listing.remove(room) {
+                    return;
+                }
+            }
+            ServerFrame::Reply(Reply::Rooms(rooms)) => {
+                self.listed_rooms = rooms.clone();
+            }
+            // An empty page, or one for a room not joined, is shown as is
+            ServerFrame::Reply(Reply::History { room, messages })
+                if self.add_history(room, messages) =>
+            {
+                return;
+            }
+            ServerFrame::Reply(Reply::Left(room) | Reply::Deleted(room)) => {
+                self.remove_room(room);
+            }
+            ServerFrame::Reply(Reply::Renamed { room, new_name }) => {
+                self.rename_room(room, new_name);
+            }
+            ServerFrame::Chat(message) if !same_room(&message.room, &self.current_room) => {
+                if let Some(view) = self.room_view_mut(&message.room) {
+                    view.unread += 1;
+                }
+            }
+            ServerFrame::Event(event) => self.apply_event(event),
+            _ => {}
+        }
+        for message in display_messages(frame) {
+            self.add_message(message);
+        }
+    }
+
+    /// Keeps member lists, and the rooms themselves, in step with the server.
+    fn apply_event(&mut self, event: &RoomEvent) {
+        match event {
+            RoomEvent::MemberJoined { room, user } => {
+                if let Some(view) = self.room_view_mut(room) {
+                    view.add_member(user);
+                }
+            }
+            RoomEvent::MemberLeft { room, user } if *user == self.nickname => {
+                // Kicked or banned
+                self.remove_room(room);
+            }
+            RoomEvent::MemberLeft { room, user } => {
+                if let Some(view) = self.room_view_mut(room) {
+                    view.remove_member(user);
+                }
+            }
+            RoomEvent::Renamed { room, new_name } => self.rename_room(room, new_name),
+            RoomEvent::Deleted(room) => self.remove_room(room),
+        }
+    }
+
+    /// Puts a page of `room`'s history above what its buffer holds. Returns
+    /// false if there was nowhere to put it.
+    fn add_history(&mut self, room: &str, messages: &[Message]) -> bool {
+        let shown = same_room(room, &self.current_room);
+        let term = self.search.as_ref().filter(|_| shown).map(|s| s.term.clone());
+        let Some(view) = self.room_view_mut(room).filter(|_| !messages.is_empty()) else {
+            return false;
+        };
+        let added = view.prepend(messages);
+        let page = view.messages.range(..added);
+        let found = match &term {
+            Some(term) => page.filter(|m| contains_term(&m.content, term)).count(),
+            None => 0,
+        };
+        if added == 0 {
+            let content = format!("The buffer of {} is full, scroll back no further", room);
+            self.add_message(Message::new(room.to_string(), "System".to_string(), content));
+        }
+        // What was found is that many messages further down now
+        if let Some(search) = self.search.as_mut().filter(|_| shown) {
+            search.at = search.at.map(|at| at + added);
+            search.matches += found;
+            search.nth += found;
+        }
+        true
+    }
+
+    /// Adds `msg` to its room's buffer. Messages of no room we are in go to
+    /// the room shown.
+    pub fn add_message(&mut self, msg: Message) {
+        let room = match self.room_view(&msg.room) {
+            Some(_) => msg.room.clone(),
+            None => self.current_room.clone(),
+        };
+        let shown = same_room(&room, &self.current_room);
+        let found = self
+            .search
+            .as_ref()
+            .is_some_and(|search| shown && contains_term(&msg.content, &search.term));
+        let dropped = match self.room_view_mut(&room) {
+            Some(view) => view.push(msg),
+            None => {
+                self.notices.push_back(msg);
+                if self.notices.len() > MAX_BUFFERED {
+                    self.notices.pop_front();
+                }
+                return;
+            }
+        };
+        // Keep the search in step with the buffer, see `Search`
+        let Some(search) = self.search.as_mut().filter(|_| shown) else {
+            return;
+        };
+        search.matches += found as usize;
+        if let Some(dropped) = dropped {
+            if contains_term(&dropped.content, &search.term) {
+                search.matches -= 1;
+                search.nth = search.nth.saturating_sub(1);
+            }
+            search.at = search.at.and_then(|at| at.checked_sub(1));
+        }
+    }
+
+}
+
+/// Converts a frame from the server into the lines the UI should show.
+fn display_messages(frame: ServerFrame) -> Vec<Message> {
+    let content = match frame {
+        ServerFrame::Chat(msg) | ServerFrame::System(msg) => return vec![msg],
+        ServerFrame::Private(msg) => {
+            let content = format!("[private] {}", msg.content);
+            // Not tied to a room, so shown whichever room is
+            return vec![Message::new(String::new(), msg.sender, content)];
+        }
+        ServerFrame::Welcome { nickname, .. } => format!("Logged in as {}", nickname),
+        ServerFrame::Reply(Reply::Joined { room, history }) => {
+            let content = format!("Joined {}", room);
+            let notice = Message::new(room, "System".to_string(), content);
+            return std::iter::once(notice).chain(history).collect();
+        }
+        ServerFrame::Reply(Reply::History { room, messages }) if messages.is_empty() => {
+            let content = format!("No older messages in {}", room);
+            return vec![Message::new(room, "System".to_string(), content)];
+        }
+        ServerFrame::Reply(Reply::History { messages, .. }) => return messages,
+        ServerFrame::Reply(Reply::Left(room)) => format!("Left {}", room),
+        ServerFrame::Reply(Reply::Rooms(rooms)) => format!("Rooms: {}", rooms.join(", ")),
+        ServerFrame::Reply(Reply::Users { room, users }) => {
+            format!("Users in {}: {}", room, users.join(", "))
+        }
+        ServerFrame::Reply(Reply::Registered(nickname)) => {
+            format!("Registered {}, use your password next time", nickname)
+        }
+        ServerFrame::Reply(Reply::Created(room)) => format!("Created {}", room),
+        ServerFrame::Reply(Reply::Renamed { room, new_name }) => {
+            format!("Renamed {} to {}", room, new_name)
+        }
+        ServerFrame::Reply(Reply::Deleted(room)) => format!("Deleted {}", room),
+        ServerFrame::Reply(Reply::RoleChanged { room, user, role }) => {
+            format!("{} is now {:?} in {}", user, role, room)
+        }
+        ServerFrame::Reply(Reply::Kicked { room, user }) => {
+            format!("Kicked {} from {}", user, room)
+        }
+        ServerFrame::Reply(Reply::Banned { room, user }) => {
+            format!("Banned {} from {}", user, room)
+        }
+        ServerFrame::Reply(Reply::Unbanned { room, user }) => {
+            format!("Unbanned {} from {}", user, room)
+        }
+        ServerFrame::Reply(Reply::Invited { room, user }) => {
+            format!("Invited {} to {}", user, room)
+        }
+        ServerFrame::Reply(Reply::ModeChanged { room, .. }) => format!("Changed mode of {}", room),
+        ServerFrame::Reply(Reply::PrivateSent { message, queued }) => {
+            let note = if queued { " (delivered when they log in)" } else { "" };
+            format!("[private to {}] {}{}", message.room, message.content, note)
+        }
+        ServerFrame::Error { code, message } => format!("Error ({}): {}", code.code(), message),
+        // Told by the system notice that comes with it
+        ServerFrame::Event(_) => return Vec::new(),
+        ServerFrame::Ping | ServerFrame::Pong => return Vec::new(),
+    };
+    vec![Message::new(String::new(), "System".to_string(), content)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message::new("lobby".to_string(), "alice".to_string(), content.to_string())
+    }
+
+    #[test]
+    fn keeps_the_newest_messages_of_a_full_buffer() {
+        let mut view = RoomView::new("lobby".to_string());
+        for i in 0..MAX_BUFFERED {
+            assert!(view.push(message(&i.to_string())).is_none());
+        }
+        let dropped = view.push(message("newest")).unwrap();
+        assert_eq!(dropped.content, "0");
+        assert_eq!(view.messages.len(), MAX_BUFFERED);
+        assert_eq!(view.messages.back().unwrap().content, "newest");
+
+        // Older history only goes in while there is room for it
+        assert_eq!(view.prepend(&[message("older")]), 0);
+        view.messages.truncate(MAX_BUFFERED - 1);
+        let page = [message("oldest"), message("older")];
+        assert_eq!(view.prepend(&page), 1);
+        assert_eq!(view.messages.front().unwrap().content, "older");
+    }
+
+    #[test]
+    fn pages_history_back_from_the_oldest_chat_message() {
+        let mut view = RoomView::new("Lobby".to_string());
+        view.push(Message::new(String::new(), "System".to_string(), "Joined".to_string()));
+        view.push(Message::new(String::new(), "bob".to_string(), "[private] hi".to_string()));
+        let first = message("first");
+        view.push(first.clone());
+        view.push(message("second"));
+        assert_eq!(view.oldest(), Some(first.id));
+
+        let page = [message("a"), message("b")];
+        view.read_marker = 4;
+        assert_eq!(view.prepend(&page), 2);
+        assert_eq!(view.oldest(), Some(page[0].id));
+        assert_eq!(view.read_marker, 6);
+        let contents: Vec<_> = view.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents[..3], ["a", "b", "Joined"]);
     }
-}
\ No newline at end of file
+}
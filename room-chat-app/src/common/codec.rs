@@ -0,0 +1,234 @@
+//! Length-prefixed framing shared by the server and the client.
+//!
+//! Every frame on the wire is a 4-byte big-endian payload length followed by
+//! that many bytes of JSON. The reader keeps its own buffer, so a frame split
+//! across several reads or several frames arriving in one read are both fine.
+
+use super::{ChatError, ChatErrorKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Room a frame needs around the text it carries: ids, names, timestamps and
+/// the JSON around them. The server keeps chat text `FRAME_OVERHEAD` bytes
+/// under the limit it reads with, and writes with that limit plus
+/// `FRAME_OVERHEAD`, so whatever it relays always fits.
+pub const FRAME_OVERHEAD: usize = 1024;
+
+const HEADER_LEN: usize = 4;
+const READ_CHUNK: usize = 4096;
+
+fn frame_too_large(len: usize, max: usize) -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Protocol,
+        message: format!("Frame of {} bytes exceeds the maximum of {} bytes", len, max),
+    }
+}
+
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    max_frame_size: usize,
+    // Bytes of an oversized frame that still have to be skipped.
+    discard: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        FrameReader {
+            inner,
+            buf: Vec::with_capacity(READ_CHUNK),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            discard: 0,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Reads and decodes the next frame, returning `None` once the peer has
+    /// closed the connection cleanly between two frames.
+    ///
+    /// An oversized frame is skipped and reported as a `Protocol` error; the
+    /// reader stays usable afterwards. Cancel safe, so it can sit in a
+    /// `tokio::select!` branch.
+    pub async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>, ChatError> {
+        loop {
+            if let Some(payload) = self.next_payload()? {
+                return Ok(Some(serde_json::from_slice(&payload)?));
+            }
+
+            self.buf.reserve(READ_CHUNK);
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() && self.discard == 0 {
+                    return Ok(None);
+                }
+                return Err(ChatError {
+                    kind: ChatErrorKind::Connection,
+                    message: "Connection closed in the middle of a frame".to_string(),
+                });
+            }
+        }
+    }
+
+    fn next_payload(&mut self) -> Result<Option<Vec<u8>>, ChatError> {
+        if self.discard > 0 {
+            self.skip_buffered();
+            if self.discard > 0 {
+                return Ok(None);
+            }
+        }
+
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&self.buf[..HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        if len > self.max_frame_size {
+            self.buf.drain(..HEADER_LEN);
+            self.discard = len;
+            self.skip_buffered();
+            return Err(frame_too_large(len, self.max_frame_size));
+        }
+
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let payload = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+        Ok(Some(payload))
+    }
+
+    fn skip_buffered(&mut self) {
+        let n = self.discard.min(self.buf.len());
+        self.buf.drain(..n);
+        self.discard -= n;
+    }
+}
+
+pub struct FrameWriter<W> {
+    inner: W,
+    max_frame_size: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        FrameWriter {
+            inner,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Encodes and writes `frame`. An oversized frame is reported as a
+    /// `Protocol` error without writing anything, so the writer stays
+    /// usable and the caller may simply drop that frame.
+    pub async fn write_frame<T: Serialize>(&mut self, frame: &T) -> Result<(), ChatError> {
+        let payload = serde_json::to_vec(frame)?;
+        if payload.len() > self.max_frame_size {
+            return Err(frame_too_large(payload.len(), self.max_frame_size));
+        }
+
+        let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        data.extend_from_slice(&payload);
+        self.inner.write_all(&data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    fn encode(payload: &str) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(payload.as_bytes());
+        data
+    }
+
+    #[tokio::test]
+    async fn reads_a_frame_split_across_reads() {
+        let (mut tx, rx) = duplex(1024);
+        let mut reader = FrameReader::new(rx);
+        let data = encode("\"hello\"");
+
+        let read = tokio::spawn(async move { reader.read_frame::<String>().await });
+        for byte in data {
+            tx.write_all(&[byte]).await.unwrap();
+            tx.flush().await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(read.await.unwrap().unwrap(), Some("hello".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reads_several_frames_from_one_read() {
+        let (mut tx, rx) = duplex(1024);
+        let mut reader = FrameReader::new(rx);
+        let mut data = encode("1");
+        data.extend(encode("2"));
+        data.extend(encode("3"));
+        tx.write_all(&data).await.unwrap();
+        drop(tx);
+
+        for expected in 1..=3 {
+            assert_eq!(reader.read_frame::<u32>().await.unwrap(), Some(expected));
+        }
+        assert_eq!(reader.read_frame::<u32>().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn skips_an_oversized_frame_and_reads_on() {
+        let (mut tx, rx) = duplex(1024);
+        let mut reader = FrameReader::new(rx).with_max_frame_size(8);
+        let mut data = encode(&format!("\"{}\"", "x".repeat(100)));
+        data.extend(encode("42"));
+        tx.write_all(&data).await.unwrap();
+        drop(tx);
+
+        let error = reader.read_frame::<String>().await.unwrap_err();
+        assert_eq!(error.kind, ChatErrorKind::Protocol);
+        assert_eq!(reader.read_frame::<u32>().await.unwrap(), Some(42));
+        assert_eq!(reader.read_frame::<u32>().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn drops_an_oversized_frame_on_write() {
+        let (tx, rx) = duplex(1024);
+        let mut writer = FrameWriter::new(tx).with_max_frame_size(8);
+        let mut reader = FrameReader::new(rx);
+
+        let error = writer.write_frame(&"x".repeat(100)).await.unwrap_err();
+        assert_eq!(error.kind, ChatErrorKind::Protocol);
+        writer.write_frame(&42u32).await.unwrap();
+        drop(writer);
+
+        assert_eq!(reader.read_frame::<u32>().await.unwrap(), Some(42));
+        assert_eq!(reader.read_frame::<u32>().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reports_a_frame_cut_short() {
+        let (mut tx, rx) = duplex(1024);
+        let mut reader = FrameReader::new(rx);
+        tx.write_all(&encode("12345")[..6]).await.unwrap();
+        drop(tx);
+
+        let error = reader.read_frame::<u32>().await.unwrap_err();
+        assert_eq!(error.kind, ChatErrorKind::Connection);
+    }
+}
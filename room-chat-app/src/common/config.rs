@@ -0,0 +1,21 @@
+//! What the client and server configurations share: reading the TOML file
+//! and reporting what is wrong with it.
+
+use super::{ChatError, ChatErrorKind};
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+
+pub fn invalid(message: String) -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Config,
+        message,
+    }
+}
+
+/// Reads a TOML config file. The result is not validated yet.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, ChatError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| invalid(format!("Cannot read {}: {}", path.display(), e)))?;
+    toml::from_str(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+}
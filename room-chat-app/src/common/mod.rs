@@ -1,31 +1,32 @@
 // src/common/mod.rs
+pub mod codec;
+pub mod config;
 pub mod message;
+pub mod protocol;
 pub mod room;
 
+pub use codec::{FrameReader, FrameWriter};
 pub use message::Message;
-pub use room::Room;
+pub use protocol::{
+    ClientFrame, Command, ModeChange, Reply, RoomEvent, ServerFrame, PROTOCOL_VERSION,
+};
+pub use room::{Role, Room, RoomMode};
 
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt;
 use std::io;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Command {
-    Join(String),    // room name
-    Leave(String),   // room name
-    Quit,
-    ListRooms,
-    ListUsers(String), // room name
-}
-
 #[derive(Debug)]
 pub struct ChatError {
     pub kind: ChatErrorKind,
     pub message: String,
 }
 
-#[derive(Debug)]
+/// What went wrong. Sent to clients in error frames as a snake_case code
+/// (see `code`), so variants must not be renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ChatErrorKind {
     Connection,
     Authentication,
@@ -33,8 +34,39 @@ pub enum ChatErrorKind {
     Message,
     Internal,
     Command,
+    #[serde(rename = "io")]
     IO,
     Serialization,
+    Protocol,
+    Permission,
+    Config,
+}
+
+impl ChatErrorKind {
+    /// The stable code this kind goes by on the wire.
+    pub fn code(self) -> &'static str {
+        match self {
+            ChatErrorKind::Connection => "connection",
+            ChatErrorKind::Authentication => "authentication",
+            ChatErrorKind::Room => "room",
+            ChatErrorKind::Message => "message",
+            ChatErrorKind::Internal => "internal",
+            ChatErrorKind::Command => "command",
+            ChatErrorKind::IO => "io",
+            ChatErrorKind::Serialization => "serialization",
+            ChatErrorKind::Protocol => "protocol",
+            ChatErrorKind::Permission => "permission",
+            ChatErrorKind::Config => "config",
+        }
+    }
+}
+
+impl ChatError {
+    /// Whether the connection the error came from is beyond use. Any other
+    /// error is reported to the peer and the session carries on.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.kind, ChatErrorKind::IO | ChatErrorKind::Connection)
+    }
 }
 
 impl fmt::Display for ChatError {
@@ -55,6 +87,15 @@ impl From<io::Error> for ChatError {
     }
 }
 
+impl From<tokio::task::JoinError> for ChatError {
+    fn from(error: tokio::task::JoinError) -> Self {
+        ChatError {
+            kind: ChatErrorKind::Internal,
+            message: error.to_string(),
+        }
+    }
+}
+
 impl From<serde_json::Error> for ChatError {
     fn from(error: serde_json::Error) -> Self {
         ChatError {
@@ -0,0 +1,494 @@
+//! Frames exchanged between the client and the server.
+//!
+//! Commands are parsed on the client and travel as structured data, so the
+//! server never has to look inside message text.
+
+use super::room::Role;
+use super::{ChatError, ChatErrorKind, Message};
+use serde::{Deserialize, Serialize};
+use std::str::{FromStr, SplitWhitespace};
+
+/// Bumped whenever the frame layout changes in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const MAX_NICKNAME_LEN: usize = 32;
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Longest timed ban, in seconds. Anything longer is better made permanent.
+pub const MAX_BAN_DURATION: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    Join {
+        room: String,
+        password: Option<String>,
+    },
+    Leave(String),   // room name
+    Quit(Option<String>), // reason shown to the rooms you were in
+    ListRooms,
+    ListUsers(String), // room name
+    Register(String),  // password for the current nickname
+    Create(String),    // room name
+    Rename { room: String, new_name: String },
+    Delete(String),    // room name
+    Op { room: String, user: String },
+    Deop { room: String, user: String },
+    Kick {
+        room: String,
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        room: String,
+        user: String,
+        duration: Option<u64>, // seconds, permanent if unset
+    },
+    Unban { room: String, user: String },
+    Invite { room: String, user: String },
+    Mode { room: String, change: ModeChange },
+    Msg { user: String, text: String },
+    History {
+        room: String,
+        limit: Option<usize>,
+        before: Option<u64>, // message id to page back from, filled in by the client
+    },
+}
+
+/// A change to a room's mode, made with `/mode`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ModeChange {
+    InviteOnly(bool),
+    Hidden(bool),
+    /// Requires a password to join, or lifts that requirement with `None`.
+    Password(Option<String>),
+}
+
+/// Frames sent from the client to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientFrame {
+    /// Must be the first frame on a connection. Registered nicknames need
+    /// their password; supplying one for a free nickname registers it.
+    Hello {
+        version: u32,
+        nickname: String,
+        password: Option<String>,
+    },
+    Chat(Message),
+    Command(Command),
+    /// Either side pings when the connection has been quiet for a while and
+    /// answers the other's pings with a pong. A session that stays silent
+    /// too long is considered dead.
+    Ping,
+    Pong,
+}
+
+/// Frames sent from the server to the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerFrame {
+    /// Accepts a `Hello`, echoing the nickname the session is now known by.
+    Welcome { version: u32, nickname: String },
+    Chat(Message),
+    System(Message),
+    /// A message for this user alone. Its `room` is the recipient's nickname.
+    Private(Message),
+    Reply(Reply),
+    /// A change to a room the client is in. Comes with a system notice
+    /// describing it for people; this is for keeping track.
+    Event(RoomEvent),
+    /// A request that failed. The session carries on unless the server closes
+    /// the connection after sending it.
+    Error { code: ChatErrorKind, message: String },
+    Ping,
+    Pong,
+}
+
+impl From<ChatError> for ServerFrame {
+    fn from(error: ChatError) -> Self {
+        ServerFrame::Error {
+            code: error.kind,
+            message: error.message,
+        }
+    }
+}
+
+/// The answer to a command, sent only to the client that issued it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Reply {
+    /// Sent after a successful join, with the room's most recent messages,
+    /// as many as fit in one frame.
+    Joined { room: String, history: Vec<Message> },
+    Left(String),
+    Rooms(Vec<String>),
+    Users { room: String, users: Vec<String> },
+    Registered(String),
+    Created(String),
+    Renamed { room: String, new_name: String },
+    Deleted(String),
+    RoleChanged { room: String, user: String, role: Role },
+    Kicked { room: String, user: String },
+    Banned { room: String, user: String },
+    Unbanned { room: String, user: String },
+    Invited { room: String, user: String },
+    ModeChanged { room: String, change: ModeChange },
+    /// A page of older messages, oldest first. Cut short to fit in one
+    /// frame, so the next page starts before the first message given.
+    History { room: String, messages: Vec<Message> },
+    /// Echoes a private message back to its sender. `queued` is set when the
+    /// recipient is offline and will get it on their next login.
+    PrivateSent { message: Message, queued: bool },
+}
+
+/// What happened to a room, sent to its members as it happens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RoomEvent {
+    MemberJoined { room: String, user: String },
+    /// The user left, went away, or was kicked or banned.
+    MemberLeft { room: String, user: String },
+    Renamed { room: String, new_name: String },
+    Deleted(String),
+}
+
+/// Nicknames are 1 to 32 ASCII letters, digits, `_` or `-`, so none can pass
+/// for another by looking alike. "System" is reserved for server notices.
+pub fn validate_nickname(nickname: &str) -> Result<(), ChatError> {
+    let valid_chars = nickname
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    let message = if nickname.is_empty() || nickname.chars().count() > MAX_NICKNAME_LEN {
+        format!("Nickname must be 1 to {} characters long", MAX_NICKNAME_LEN)
+    } else if !valid_chars {
+        "Nickname may only contain ASCII letters, digits, '_' and '-'".to_string()
+    } else if nickname.eq_ignore_ascii_case("system") {
+        "Nickname is reserved".to_string()
+    } else {
+        return Ok(());
+    };
+    Err(ChatError {
+        kind: ChatErrorKind::Authentication,
+        message,
+    })
+}
+
+/// What nicknames are told apart by: two that differ only in case belong to
+/// the same user.
+pub fn nickname_key(nickname: &str) -> String {
+    nickname.to_ascii_lowercase()
+}
+
+/// Whether `a` and `b` name the same user, see [`nickname_key`].
+pub fn same_nickname(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// The form a room name is looked up by. Room names are ASCII, see
+/// [`validate_room_name`], so two names that differ only in case are the
+/// same room.
+pub fn room_key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Whether `a` and `b` name the same room, see [`room_key`].
+pub fn same_room(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Room names are 1 to 32 ASCII letters, digits, `_`, `-` or `.`. Like
+/// nicknames they are kept to ASCII so that no two of them look alike.
+pub fn validate_room_name(name: &str) -> Result<(), ChatError> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    let message = if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LEN {
+        format!("Room name must be 1 to {} characters long", MAX_ROOM_NAME_LEN)
+    } else if !valid_chars {
+        "Room name may only contain ASCII letters, digits, '_', '-' and '.'".to_string()
+    } else {
+        return Ok(());
+    };
+    Err(ChatError {
+        kind: ChatErrorKind::Room,
+        message,
+    })
+}
+
+fn required(args: &mut SplitWhitespace, usage: &str) -> Result<String, ChatError> {
+    args.next().map(str::to_string).ok_or(ChatError {
+        kind: ChatErrorKind::Command,
+        message: format!("Usage: {}", usage),
+    })
+}
+
+fn optional<T: FromStr>(args: &mut SplitWhitespace, usage: &str) -> Result<Option<T>, ChatError> {
+    args.next()
+        .map(|arg| {
+            arg.parse().map_err(|_| ChatError {
+                kind: ChatErrorKind::Command,
+                message: format!("Usage: {}", usage),
+            })
+        })
+        .transpose()
+}
+
+/// The text after the first `tokens` words of `line`, spacing untouched.
+/// `None` if nothing is left.
+fn text_after(line: &str, tokens: usize) -> Option<String> {
+    let mut rest = line.trim_start();
+    for _ in 0..tokens {
+        rest = rest
+            .trim_start_matches(|c: char| !c.is_whitespace())
+            .trim_start();
+    }
+    (!rest.is_empty()).then(|| rest.to_string())
+}
+
+/// Parses `30s`, `10m`, `2h` or `1d` into seconds. A bare number is seconds.
+/// Durations over `MAX_BAN_DURATION` are refused.
+fn parse_duration(arg: &str) -> Option<u64> {
+    let (number, unit) = match arg.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&arg[..i], c),
+        _ => (arg, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let seconds = number.parse::<u64>().ok()?.checked_mul(multiplier)?;
+    (seconds <= MAX_BAN_DURATION).then_some(seconds)
+}
+
+impl Command {
+    /// Parses a `/command` line. Moderation commands act on `current_room`,
+    /// the room the user is looking at.
+    pub fn parse(line: &str, current_room: &str) -> Result<Self, ChatError> {
+        let room = current_room.to_string();
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some("/join") => Command::Join {
+                room: required(&mut args, "/join <room> [password]")?,
+                password: args.next().map(str::to_string),
+            },
+            Some("/leave") => Command::Leave(required(&mut args, "/leave <room>")?),
+            Some("/list") => Command::ListRooms,
+            Some("/users") => Command::ListUsers(required(&mut args, "/users <room>")?),
+            Some("/quit") => Command::Quit(text_after(line, 1)),
+            Some("/register") => Command::Register(required(&mut args, "/register <password>")?),
+            Some("/create") => Command::Create(required(&mut args, "/create <room>")?),
+            Some("/rename") => {
+                let usage = "/rename <old> <new>";
+                Command::Rename {
+                    room: required(&mut args, usage)?,
+                    new_name: required(&mut args, usage)?,
+                }
+            }
+            Some("/delete") => Command::Delete(required(&mut args, "/delete <room>")?),
+            Some("/op") => Command::Op {
+                room,
+                user: required(&mut args, "/op <user>")?,
+            },
+            Some("/deop") => Command::Deop {
+                room,
+                user: required(&mut args, "/deop <user>")?,
+            },
+            Some("/kick") => Command::Kick {
+                room,
+                user: required(&mut args, "/kick <user> [reason]")?,
+                reason: text_after(line, 2),
+            },
+            Some("/ban") => {
+                let usage = "/ban <user> [duration up to 365d, e.g. 30s, 10m, 2h, 1d]";
+                let user = required(&mut args, usage)?;
+                let duration = match args.next() {
+                    Some(arg) => Some(parse_duration(arg).ok_or(ChatError {
+                        kind: ChatErrorKind::Command,
+                        message: format!("Usage: {}", usage),
+                    })?),
+                    None => None,
+                };
+                Command::Ban {
+                    room,
+                    user,
+                    duration,
+                }
+            }
+            Some("/unban") => Command::Unban {
+                room,
+                user: required(&mut args, "/unban <user>")?,
+            },
+            Some("/invite") => Command::Invite {
+                room,
+                user: required(&mut args, "/invite <user>")?,
+            },
+            Some("/mode") => {
+                let usage = "/mode <+i|-i|+h|-h|+k <password>|-k>";
+                let change = match required(&mut args, usage)?.as_str() {
+                    "+i" => ModeChange::InviteOnly(true),
+                    "-i" => ModeChange::InviteOnly(false),
+                    "+h" => ModeChange::Hidden(true),
+                    "-h" => ModeChange::Hidden(false),
+                    "+k" => ModeChange::Password(Some(required(&mut args, usage)?)),
+                    "-k" => ModeChange::Password(None),
+                    _ => {
+                        return Err(ChatError {
+                            kind: ChatErrorKind::Command,
+                            message: format!("Usage: {}", usage),
+                        })
+                    }
+                };
+                Command::Mode { room, change }
+            }
+            Some("/msg") => {
+                let usage = "/msg <user> <text>";
+                Command::Msg {
+                    user: required(&mut args, usage)?,
+                    text: text_after(line, 2).ok_or(ChatError {
+                        kind: ChatErrorKind::Command,
+                        message: format!("Usage: {}", usage),
+                    })?,
+                }
+            }
+            Some("/history") => {
+                let usage = "/history <room> [n]";
+                Command::History {
+                    room: required(&mut args, usage)?,
+                    limit: optional(&mut args, usage)?,
+                    before: None,
+                }
+            }
+            _ => {
+                return Err(ChatError {
+                    kind: ChatErrorKind::Command,
+                    message: "Unknown command".to_string(),
+                })
+            }
+        };
+        Ok(command)
+    }
+}
+
+impl ClientFrame {
+    /// Turns a line typed by the user into a frame: lines starting with `/`
+    /// are commands, everything else is chat for `room`.
+    pub fn from_input(room: &str, sender: &str, input: &str) -> Result<Self, ChatError> {
+        if input.starts_with('/') {
+            Ok(ClientFrame::Command(Command::parse(input, room)?))
+        } else {
+            Ok(ClientFrame::Chat(Message::new(
+                room.to_string(),
+                sender.to_string(),
+                input.to_string(),
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage_error(line: &str) -> String {
+        Command::parse(line, "lobby").unwrap_err().message
+    }
+
+    #[test]
+    fn tells_how_to_use_commands_missing_arguments() {
+        let cases = [
+            ("/join", "/join <room> [password]"),
+            ("/leave", "/leave <room>"),
+            ("/users", "/users <room>"),
+            ("/register", "/register <password>"),
+            ("/create", "/create <room>"),
+            ("/rename lobby", "/rename <old> <new>"),
+            ("/delete", "/delete <room>"),
+            ("/op", "/op <user>"),
+            ("/deop", "/deop <user>"),
+            ("/kick", "/kick <user> [reason]"),
+            ("/ban", "/ban <user> [duration up to 365d, e.g. 30s, 10m, 2h, 1d]"),
+            ("/unban", "/unban <user>"),
+            ("/invite", "/invite <user>"),
+            ("/mode", "/mode <+i|-i|+h|-h|+k <password>|-k>"),
+            ("/mode +x", "/mode <+i|-i|+h|-h|+k <password>|-k>"),
+            ("/mode +k", "/mode <+i|-i|+h|-h|+k <password>|-k>"),
+            ("/msg", "/msg <user> <text>"),
+            ("/msg bob   ", "/msg <user> <text>"),
+            ("/history", "/history <room> [n]"),
+            ("/history lobby ten", "/history <room> [n]"),
+        ];
+        for (line, usage) in cases {
+            assert_eq!(usage_error(line), format!("Usage: {}", usage), "{}", line);
+        }
+        assert_eq!(usage_error("/dance"), "Unknown command");
+    }
+
+    #[test]
+    fn keeps_reasons_and_message_text_as_typed() {
+        match Command::parse("/quit  gone   fishing ", "lobby").unwrap() {
+            Command::Quit(reason) => assert_eq!(reason.as_deref(), Some("gone   fishing ")),
+            command => panic!("parsed as {:?}", command),
+        }
+        assert!(matches!(Command::parse("/quit", "lobby").unwrap(), Command::Quit(None)));
+
+        match Command::parse("/kick bob  spamming, twice", "lobby").unwrap() {
+            Command::Kick { room, user, reason } => {
+                assert_eq!((room.as_str(), user.as_str()), ("lobby", "bob"));
+                assert_eq!(reason.as_deref(), Some("spamming, twice"));
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+        assert!(matches!(
+            Command::parse("/kick bob", "lobby").unwrap(),
+            Command::Kick { reason: None, .. }
+        ));
+
+        match Command::parse("/msg bob   two  spaces\tand a tab", "lobby").unwrap() {
+            Command::Msg { user, text } => {
+                assert_eq!(user, "bob");
+                assert_eq!(text, "two  spaces\tand a tab");
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parses_ban_durations_up_to_a_year() {
+        let cases = [
+            ("30s", Some(30)),
+            ("45", Some(45)),
+            ("10m", Some(600)),
+            ("2h", Some(7200)),
+            ("1d", Some(86400)),
+            ("365d", Some(MAX_BAN_DURATION)),
+            ("366d", None),
+            ("99999999999d", None),
+            ("1x", None),
+            ("m", None),
+            ("-1d", None),
+            ("", None),
+        ];
+        for (arg, seconds) in cases {
+            assert_eq!(parse_duration(arg), seconds, "{}", arg);
+        }
+    }
+
+    #[test]
+    fn refuses_bans_with_a_bad_duration() {
+        let usage = "Usage: /ban <user> [duration up to 365d, e.g. 30s, 10m, 2h, 1d]";
+        for duration in ["99999999999d", "366d", "1x", "m"] {
+            let line = format!("/ban bob {}", duration);
+            assert_eq!(usage_error(&line), usage, "{}", line);
+        }
+        match Command::parse("/ban bob 10m", "lobby").unwrap() {
+            Command::Ban { user, duration, .. } => {
+                assert_eq!(user, "bob");
+                assert_eq!(duration, Some(600));
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+        assert!(matches!(
+            Command::parse("/ban bob", "lobby").unwrap(),
+            Command::Ban { duration: None, .. }
+        ));
+    }
+}
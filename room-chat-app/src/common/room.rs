@@ -1,12 +1,50 @@
+use super::protocol::same_nickname;
 use super::Message;
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::SystemTime;
 
-const MAX_HISTORY: usize = 100;
+/// Messages a room keeps unless configured otherwise.
+pub const DEFAULT_MAX_HISTORY: usize = 100;
 
-#[derive(Debug, Clone)]
+/// Who may join a room and whether it shows up in room listings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomMode {
+    pub invite_only: bool,
+    pub hidden: bool,
+    /// argon2 hash of the password needed to join, if any.
+    pub password_hash: Option<String>,
+}
+
+/// What a user may do in a room, ordered from least to most privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
     pub name: String,
+    // Only the connected members, so a restarted server starts with none
+    #[serde(skip)]
     pub users: Vec<String>,
+    /// The user who created the room, `None` for rooms made by the server.
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub moderators: Vec<String>,
+    /// Banned users and when their ban ends, `None` for permanent bans.
+    #[serde(default)]
+    pub bans: HashMap<String, Option<SystemTime>>,
+    #[serde(default)]
+    pub mode: RoomMode,
+    /// Users invited to join, regardless of the room's mode.
+    #[serde(default)]
+    pub invited: Vec<String>,
+    // Persisted message by message, not as part of the room
+    #[serde(skip)]
     pub history: VecDeque<Message>,
 }
 
@@ -15,19 +53,120 @@ impl Room {
         Room {
             name,
             users: Vec::new(),
-            history: VecDeque::with_capacity(MAX_HISTORY),
+            owner: None,
+            moderators: Vec::new(),
+            bans: HashMap::new(),
+            mode: RoomMode::default(),
+            invited: Vec::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// A copy of what is stored as the room itself: everything but the
+    /// members, and the history, which is stored message by message.
+    pub fn to_stored(&self) -> Room {
+        Room {
+            name: self.name.clone(),
+            users: Vec::new(),
+            owner: self.owner.clone(),
+            moderators: self.moderators.clone(),
+            bans: self.bans.clone(),
+            mode: self.mode.clone(),
+            invited: self.invited.clone(),
+            history: VecDeque::new(),
         }
     }
 
-    pub fn add_message(&mut self, message: Message) {
-        if self.history.len() >= MAX_HISTORY {
+    /// Appends to the history, dropping the oldest messages beyond
+    /// `max_history`.
+    pub fn add_message(&mut self, message: Message, max_history: usize) {
+        while self.history.len() >= max_history.max(1) {
             self.history.pop_front();
         }
         self.history.push_back(message);
     }
 
+    /// Up to `limit` messages from the history, oldest first. With `before`
+    /// set, only messages older than the message with that id are returned.
+    pub fn history_page(&self, limit: usize, before: Option<u64>) -> Option<Vec<Message>> {
+        let end = match before {
+            Some(id) => self.history.iter().position(|m| m.id == id)?,
+            None => self.history.len(),
+        };
+        let start = end.saturating_sub(limit);
+        Some(self.history.range(start..end).cloned().collect())
+    }
+
+    /// The role of `username` here. Like every check on `Room`, this matches
+    /// nicknames regardless of case.
+    pub fn role(&self, username: &str) -> Role {
+        if self.owner.as_deref().is_some_and(|o| same_nickname(o, username)) {
+            Role::Owner
+        } else if self.moderators.iter().any(|m| same_nickname(m, username)) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
+    }
+
+    /// Whether `username` is currently banned, forgetting bans that ran out.
+    pub fn is_banned(&mut self, username: &str) -> bool {
+        let ban = self.bans.iter().find(|(b, _)| same_nickname(b, username));
+        match ban {
+            Some((banned, Some(until))) if *until <= SystemTime::now() => {
+                let banned = banned.clone();
+                self.bans.remove(&banned);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Bans `username` until `until`, or for good when `None`, replacing any
+    /// ban they already had.
+    pub fn ban(&mut self, username: &str, until: Option<SystemTime>) {
+        self.unban(username);
+        self.bans.insert(username.to_string(), until);
+    }
+
+    /// Lifts the ban on `username`. Returns `false` if there was none.
+    pub fn unban(&mut self, username: &str) -> bool {
+        let bans = self.bans.len();
+        self.bans.retain(|banned, _| !same_nickname(banned, username));
+        self.bans.len() != bans
+    }
+
+    pub fn set_moderator(&mut self, username: &str, moderator: bool) {
+        self.moderators.retain(|m| !same_nickname(m, username));
+        if moderator {
+            self.moderators.push(username.to_string());
+        }
+    }
+
+    pub fn is_member(&self, username: &str) -> bool {
+        self.users.iter().any(|u| same_nickname(u, username))
+    }
+
+    pub fn is_invited(&self, username: &str) -> bool {
+        self.invited.iter().any(|u| same_nickname(u, username))
+    }
+
+    pub fn invite(&mut self, username: &str) {
+        if !self.is_invited(username) {
+            self.invited.push(username.to_string());
+        }
+    }
+
+    /// Uses up the invitation of `username`. Returns `false` if they had none.
+    pub fn take_invitation(&mut self, username: &str) -> bool {
+        let invitations = self.invited.len();
+        self.invited.retain(|u| !same_nickname(u, username));
+        self.invited.len() != invitations
+    }
+
     pub fn add_user(&mut self, username: String) -> bool {
-        if !self.users.contains(&username) {
+        if !self.is_member(&username) {
             self.users.push(username);
             true
         } else {
@@ -36,7 +175,7 @@ impl Room {
     }
 
     pub fn remove_user(&mut self, username: &str) -> bool {
-        if let Some(pos) = self.users.iter().position(|x| x == username) {
+        if let Some(pos) = self.users.iter().position(|x| same_nickname(x, username)) {
             self.users.remove(pos);
             true
         } else {
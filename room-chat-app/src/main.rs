@@ -1,15 +1,17 @@
-use crate::common::ChatError;
-use crate::server::ChatServer;
-
-pub mod common;
-pub mod server;
-pub mod client;
+use room_chat_app::common::ChatError;
+use std::process;
+use room_chat_app::server::{ChatServer, ServerConfig};
 
 #[tokio::main]
 async fn main() -> Result<(), ChatError> {
-    let addr = "127.0.0.1:8080";
-    let server = ChatServer::new(addr).await?;
-    println!("Chat server running on {}", addr);
+    let config = ServerConfig::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let server = ChatServer::new(config).await.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
     server.run().await?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,38 +1,308 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::mpsc;
-use crate::common::{ChatError, Message};
+use tokio::sync::mpsc::error::TrySendError;
+use crate::common::protocol::nickname_key;
+use crate::common::{ChatError, Message, ServerFrame};
 
+/// Frames queued for a session before further ones are dropped, unless
+/// configured otherwise.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 100;
+/// Dropped frames after which a lagging session is disconnected.
+pub const MAX_MISSED: usize = 1000;
+/// Private messages kept for a user who is offline.
+pub const MAX_PENDING: usize = 100;
+
+/// Delivers frames to one session without ever waiting on it. Rooms keep a
+/// clone for each member who is online.
+#[derive(Clone)]
+pub struct SessionSender {
+    tx: mpsc::Sender<ServerFrame>,
+    lag: Arc<Lag>,
+}
+
+// Shared between a session's senders and its outbox
+#[derive(Default)]
+struct Lag {
+    // Frames dropped since the outbox last drained
+    missed: AtomicUsize,
+    // Set once the session missed too many, which ends its connection handler
+    cut_off: AtomicBool,
+}
+
+impl SessionSender {
+    /// Queues `frame`. A full queue drops the frame, and a session that keeps
+    /// missing frames is cut off. Returns `false` if the session is gone.
+    pub fn send(&self, frame: ServerFrame) -> bool {
+        if self.lag.cut_off.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        match self.tx.try_send(frame) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if self.lag.missed.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_MISSED {
+                    self.lag.cut_off.store(true, Ordering::Relaxed);
+                    return false;
+                }
+                true
+            }
+            // The connection handler is on its way out
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// A logged-in connection.
+pub struct Session {
+    sender: SessionSender,
+    /// The nickname as the user logged in with it.
+    pub nickname: String,
+    pub rooms: HashSet<String>,
+    pub connected_at: SystemTime,
+    pub addr: SocketAddr,
+}
+
+/// The frames waiting to be written to one session.
+pub struct Outbox {
+    rx: mpsc::Receiver<ServerFrame>,
+    lag: Arc<Lag>,
+}
+
+impl Outbox {
+    /// Waits for the next frame to write. Once the queue has drained after
+    /// overflowing, yields a notice of how many frames were dropped. Returns
+    /// `None` if the session was cut off for lagging. Cancel safe.
+    pub async fn recv(&mut self) -> Option<ServerFrame> {
+        if self.rx.is_empty() {
+            let missed = self.lag.missed.swap(0, Ordering::Relaxed);
+            if missed > 0 {
+                let notice = Message::new(
+                    String::new(),
+                    "System".to_string(),
+                    format!("You missed {} messages", missed),
+                );
+                return Some(ServerFrame::System(notice));
+            }
+            // Nothing is queued after the cut-off, so everything before it
+            // has been written by now
+            if self.lag.cut_off.load(Ordering::Relaxed) {
+                return None;
+            }
+        }
+        self.rx.recv().await
+    }
+}
+
+/// The one registry of connected users. Connection handlers add and remove
+/// sessions; rooms keep each session's joined rooms up to date and take a
+/// `SessionSender` for each member. Nicknames are looked up regardless of
+/// case, see [`nickname_key`].
 pub struct ClientManager {
-    clients: HashMap<String, mpsc::Sender<Message>>,
+    sessions: HashMap<String, Session>,
+    // Private messages waiting for their recipient to log in
+    pending: HashMap<String, Vec<Message>>,
+    max_clients: Option<usize>,
+    queue_capacity: usize,
+}
+
+impl Default for ClientManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientManager {
     pub fn new() -> Self {
         ClientManager {
-            clients: HashMap::new(),
+            sessions: HashMap::new(),
+            pending: HashMap::new(),
+            max_clients: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
         }
     }
 
+    /// Turns away logins once `max_clients` sessions are open.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// Sets how many frames are queued for each session, see `SessionSender`.
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity.max(1);
+        self
+    }
+
+    /// Registers a session for `username` and returns the outbox of frames to
+    /// write to it.
     pub async fn add_client(
         &mut self,
         username: String,
-        tx: mpsc::Sender<Message>,
-    ) -> Result<(), ChatError> {
-        if self.clients.contains_key(&username) {
+        addr: SocketAddr,
+    ) -> Result<Outbox, ChatError> {
+        let key = nickname_key(&username);
+        if self.sessions.contains_key(&key) {
             return Err(ChatError {
                 kind: crate::common::ChatErrorKind::Authentication,
                 message: "Username already taken".to_string(),
             });
         }
-        self.clients.insert(username, tx);
+        if self.max_clients.is_some_and(|max| self.sessions.len() >= max) {
+            return Err(ChatError {
+                kind: crate::common::ChatErrorKind::Connection,
+                message: "Server is full".to_string(),
+            });
+        }
+        let (tx, rx) = mpsc::channel(self.queue_capacity);
+        let lag = Arc::new(Lag::default());
+        let session = Session {
+            sender: SessionSender {
+                tx,
+                lag: Arc::clone(&lag),
+            },
+            nickname: username,
+            rooms: HashSet::new(),
+            connected_at: SystemTime::now(),
+            addr,
+        };
+        self.sessions.insert(key, session);
+        Ok(Outbox { rx, lag })
+    }
+
+    pub fn session(&self, username: &str) -> Option<&Session> {
+        self.sessions.get(&nickname_key(username))
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.sessions.contains_key(&nickname_key(username))
+    }
+
+    /// Queues `frame` for `username` without waiting on them, so one slow
+    /// reader cannot hold up everyone else. Returns `false` if they are not
+    /// connected.
+    pub fn send(&self, username: &str, frame: ServerFrame) -> bool {
+        match self.session(username) {
+            Some(session) => session.sender.send(frame),
+            None => false,
+        }
+    }
+
+    /// Records that `username` joined `room` and returns the sender the room
+    /// should deliver through, or `None` if they are not connected.
+    pub fn joined(&mut self, username: &str, room: &str) -> Option<SessionSender> {
+        let session = self.sessions.get_mut(&nickname_key(username))?;
+        session.rooms.insert(room.to_string());
+        Some(session.sender.clone())
+    }
+
+    /// Records that `username` is no longer in `room`.
+    pub fn left(&mut self, username: &str, room: &str) {
+        if let Some(session) = self.sessions.get_mut(&nickname_key(username)) {
+            session.rooms.remove(room);
+        }
+    }
+
+    pub fn room_renamed(&mut self, old_name: &str, new_name: &str) {
+        for session in self.sessions.values_mut() {
+            if session.rooms.remove(old_name) {
+                session.rooms.insert(new_name.to_string());
+            }
+        }
+    }
+
+    /// Delivers a private message to `message.room`, the recipient's
+    /// nickname. Returns `false` if the recipient is not connected.
+    pub fn send_private(&self, message: Message) -> bool {
+        let recipient = message.room.clone();
+        self.send(&recipient, ServerFrame::Private(message))
+    }
+
+    /// Keeps a private message until its recipient next logs in, unless
+    /// `MAX_PENDING` are already waiting for them.
+    pub fn queue_private(&mut self, message: Message) -> Result<(), ChatError> {
+        let pending = self.pending.entry(nickname_key(&message.room)).or_default();
+        if pending.len() >= MAX_PENDING {
+            return Err(ChatError {
+                kind: crate::common::ChatErrorKind::Message,
+                message: format!("{} has too many messages waiting", message.room),
+            });
+        }
+        pending.push(message);
         Ok(())
     }
 
-    pub async fn remove_client(&mut self, username: &str) -> Result<(), ChatError> {
-        self.clients.remove(username).ok_or(ChatError {
+    pub fn take_pending(&mut self, username: &str) -> Vec<Message> {
+        self.pending.remove(&nickname_key(username)).unwrap_or_default()
+    }
+
+    /// Puts back private messages taken with `take_pending` that could not be
+    /// delivered, ahead of any queued since.
+    pub fn restore_pending(&mut self, username: &str, mut messages: Vec<Message>) {
+        let pending = self.pending.entry(nickname_key(username)).or_default();
+        messages.append(pending);
+        *pending = messages;
+    }
+
+    pub async fn remove_client(&mut self, username: &str) -> Result<Session, ChatError> {
+        self.sessions.remove(&nickname_key(username)).ok_or(ChatError {
             kind: crate::common::ChatErrorKind::Authentication,
             message: "Client not found".to_string(),
-        })?;
-        Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn missed_notice(frame: Option<ServerFrame>) -> String {
+        match frame {
+            Some(ServerFrame::System(notice)) => notice.content,
+            _ => panic!("expected a notice of missed messages"),
+        }
+    }
+
+    async fn lagging_session(queue_capacity: usize) -> (SessionSender, Outbox) {
+        let mut clients = ClientManager::new().with_queue_capacity(queue_capacity);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let outbox = clients.add_client("alice".to_string(), addr).await.unwrap();
+        let sender = clients.joined("alice", "lobby").unwrap();
+        (sender, outbox)
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn drops_frames_beyond_the_queue_and_says_so_once_drained() {
+        let (sender, mut outbox) = lagging_session(2).await;
+        for _ in 0..5 {
+            assert!(sender.send(ServerFrame::Pong));
+        }
+
+        assert!(matches!(outbox.recv().await, Some(ServerFrame::Pong)));
+        assert!(matches!(outbox.recv().await, Some(ServerFrame::Pong)));
+        assert_eq!(missed_notice(outbox.recv().await), "You missed 3 messages");
+        // The count starts over and the queue takes frames again
+        assert!(sender.send(ServerFrame::Ping));
+        assert!(matches!(outbox.recv().await, Some(ServerFrame::Ping)));
+    }
+
+    #[tokio::test]
+    async fn cuts_off_a_session_that_misses_too_many() {
+        let (sender, mut outbox) = lagging_session(1).await;
+        assert!(sender.send(ServerFrame::Pong));
+        for _ in 1..MAX_MISSED {
+            assert!(sender.send(ServerFrame::Ping));
+        }
+        assert!(!sender.send(ServerFrame::Ping));
+        assert!(!sender.send(ServerFrame::Ping));
+
+        // What was queued is still written, then the session ends
+        assert!(matches!(outbox.recv().await, Some(ServerFrame::Pong)));
+        let notice = format!("You missed {} messages", MAX_MISSED);
+        assert_eq!(missed_notice(outbox.recv().await), notice);
+        assert!(outbox.recv().await.is_none());
+    }
+}
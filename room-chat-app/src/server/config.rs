@@ -0,0 +1,215 @@
+use super::client_manager::DEFAULT_QUEUE_CAPACITY;
+use super::room_manager::DEFAULT_HISTORY_REPLAY;
+use crate::common::codec::{DEFAULT_MAX_FRAME_SIZE, FRAME_OVERHEAD};
+use crate::common::config::{self, invalid};
+use crate::common::protocol::validate_room_name;
+use crate::common::room::DEFAULT_MAX_HISTORY;
+use crate::common::ChatError;
+use clap::Parser;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Smallest frame limit that still fits a login and a short chat line,
+/// leaving `FRAME_OVERHEAD` for what the server wraps around it.
+const MIN_MESSAGE_SIZE: usize = 2 * FRAME_OVERHEAD;
+
+/// Command-line flags. Each overrides the matching setting of the config file.
+#[derive(Debug, Parser)]
+#[command(about = "Runs the room chat server")]
+pub struct ServerArgs {
+    /// TOML file to read settings from
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Address to listen on, may be given more than once
+    #[arg(short, long = "listen", value_name = "ADDR")]
+    listen: Vec<SocketAddr>,
+    /// Room to create at startup if it does not exist, may be given more than once
+    #[arg(long = "default-room", value_name = "ROOM")]
+    default_rooms: Vec<String>,
+    /// Messages kept per room
+    #[arg(long, value_name = "N")]
+    history_length: Option<usize>,
+    /// Messages replayed to a user joining a room
+    #[arg(long, value_name = "N")]
+    history_replay: Option<usize>,
+    /// Connected users at most
+    #[arg(long, value_name = "N")]
+    max_clients: Option<usize>,
+    /// Largest frame a client may send, in bytes, up to 65536
+    #[arg(long, value_name = "BYTES")]
+    max_message_size: Option<usize>,
+    /// Frames queued per client before it starts missing messages
+    #[arg(long, value_name = "N")]
+    queue_capacity: Option<usize>,
+    /// Message of the day, shown to every user after logging in
+    #[arg(long, value_name = "TEXT")]
+    motd: Option<String>,
+    /// Where rooms and history are persisted
+    #[arg(long, value_name = "FILE")]
+    storage_path: Option<PathBuf>,
+    /// Where registered nicknames are kept
+    #[arg(long, value_name = "FILE")]
+    credentials_path: Option<PathBuf>,
+    /// Only let registered nicknames log in
+    #[arg(long)]
+    no_guests: bool,
+}
+
+/// Everything a `ChatServer` can be told, read from a TOML file whose keys
+/// match the field names. Missing keys keep their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,
+    /// Rooms created at startup unless they were restored from storage.
+    pub default_rooms: Vec<String>,
+    pub history_length: usize,
+    pub history_replay: usize,
+    pub max_clients: usize,
+    pub max_message_size: usize,
+    pub queue_capacity: usize,
+    pub motd: Option<String>,
+    pub storage_path: PathBuf,
+    pub credentials_path: PathBuf,
+    pub allow_guests: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 8080))],
+            default_rooms: vec!["lobby".to_string()],
+            history_length: DEFAULT_MAX_HISTORY,
+            history_replay: DEFAULT_HISTORY_REPLAY,
+            max_clients: 1000,
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            motd: None,
+            storage_path: PathBuf::from("rooms.log"),
+            credentials_path: PathBuf::from("credentials.json"),
+            allow_guests: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the command line and the config file it
+    /// names, and validates it.
+    pub fn from_args() -> Result<Self, ChatError> {
+        Self::from_cli(ServerArgs::parse())
+    }
+
+    pub fn from_cli(args: ServerArgs) -> Result<Self, ChatError> {
+        let mut config = match &args.config {
+            Some(path) => config::load(path)?,
+            None => Self::default(),
+        };
+
+        if !args.listen.is_empty() {
+            config.listen = args.listen;
+        }
+        if !args.default_rooms.is_empty() {
+            config.default_rooms = args.default_rooms;
+        }
+        config.history_length = args.history_length.unwrap_or(config.history_length);
+        config.history_replay = args.history_replay.unwrap_or(config.history_replay);
+        config.max_clients = args.max_clients.unwrap_or(config.max_clients);
+        config.max_message_size = args.max_message_size.unwrap_or(config.max_message_size);
+        config.queue_capacity = args.queue_capacity.unwrap_or(config.queue_capacity);
+        config.motd = args.motd.or(config.motd);
+        config.storage_path = args.storage_path.unwrap_or(config.storage_path);
+        config.credentials_path = args.credentials_path.unwrap_or(config.credentials_path);
+        config.allow_guests &= !args.no_guests;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ChatError> {
+        if self.listen.is_empty() {
+            return Err(invalid("listen needs at least one address".to_string()));
+        }
+        for room in &self.default_rooms {
+            validate_room_name(room)
+                .map_err(|e| invalid(format!("default_rooms: {}: {}", room, e.message)))?;
+        }
+        let at_least_one = [
+            ("history_length", self.history_length),
+            ("max_clients", self.max_clients),
+            ("queue_capacity", self.queue_capacity),
+        ];
+        for (name, value) in at_least_one {
+            if value == 0 {
+                return Err(invalid(format!("{} must be at least 1", name)));
+            }
+        }
+        // Frames are relayed to clients, which read no more than the default
+        if !(MIN_MESSAGE_SIZE..=DEFAULT_MAX_FRAME_SIZE).contains(&self.max_message_size) {
+            return Err(invalid(format!(
+                "max_message_size must be between {} and {} bytes",
+                MIN_MESSAGE_SIZE, DEFAULT_MAX_FRAME_SIZE
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `flags` as the server's command line, with `--config` pointing
+    /// at a file holding `toml` when it is given.
+    fn from_cli(test: &str, toml: Option<&str>, flags: &[&str]) -> Result<ServerConfig, ChatError> {
+        let file = format!("room-chat-{}-{}.toml", test, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let mut argv = vec!["server".to_string()];
+        if let Some(toml) = toml {
+            std::fs::write(&path, toml).unwrap();
+            argv.extend(["--config".to_string(), path.display().to_string()]);
+        }
+        argv.extend(flags.iter().map(|flag| flag.to_string()));
+        let config = ServerConfig::from_cli(ServerArgs::try_parse_from(argv).unwrap());
+        let _ = std::fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn lets_flags_override_the_config_file() {
+        let toml = "max_clients = 10\nhistory_length = 7\nmotd = \"hello\"\n";
+        let flags = ["--max-clients", "5", "--listen", "0.0.0.0:9000"];
+        let config = from_cli("server-flags", Some(toml), &flags).unwrap();
+        assert_eq!(config.max_clients, 5);
+        assert_eq!(config.listen, [SocketAddr::from(([0, 0, 0, 0], 9000))]);
+        // What no flag was given for comes from the file, then the defaults
+        assert_eq!(config.history_length, 7);
+        assert_eq!(config.motd.as_deref(), Some("hello"));
+        assert_eq!(config.history_replay, DEFAULT_HISTORY_REPLAY);
+    }
+
+    #[test]
+    fn turns_guests_away_from_the_flag_or_the_file() {
+        assert!(from_cli("guests-default", None, &[]).unwrap().allow_guests);
+        let config = from_cli("no-guests-flag", None, &["--no-guests"]).unwrap();
+        assert!(!config.allow_guests);
+        // The flag can only take guests away, never let them back in
+        let config = from_cli("no-guests-file", Some("allow_guests = false\n"), &[]).unwrap();
+        assert!(!config.allow_guests);
+    }
+
+    #[test]
+    fn refuses_invalid_settings() {
+        let cases: [(&str, &[&str]); 4] = [
+            ("max_clients = 0\n", &[]),
+            ("", &["--max-message-size", "100000"]),
+            ("default_rooms = [\"caf\u{e9}\"]\n", &[]),
+            ("max_clients = 5\nmax_users = 5\n", &[]),
+        ];
+        for (i, (toml, flags)) in cases.into_iter().enumerate() {
+            let test = format!("invalid-server-config-{}", i);
+            let error = from_cli(&test, Some(toml), flags).unwrap_err();
+            assert_eq!(error.kind, crate::common::ChatErrorKind::Config, "{}", toml);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::common::protocol::nickname_key;
+use crate::common::{ChatError, ChatErrorKind};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use tokio::sync::Semaphore;
+
+/// Passwords hashed or checked at once at most. Each takes argon2's default
+/// 19 MiB and a core for a while, so unauthenticated clients must not be able
+/// to start them without limit.
+const MAX_CONCURRENT_HASHES: usize = 4;
+
+static HASHING: Semaphore = Semaphore::const_new(MAX_CONCURRENT_HASHES);
+
+/// Registered nicknames and their salted argon2 password hashes, kept in a
+/// JSON file so accounts survive restarts. Nicknames are looked up
+/// regardless of case, see [`nickname_key`].
+pub struct CredentialStore {
+    path: PathBuf,
+    // The nickname as registered and its hash, by `nickname_key`
+    accounts: HashMap<String, (String, String)>,
+    allow_guests: bool,
+}
+
+impl CredentialStore {
+    /// Loads the store from `path`, starting empty if the file does not exist
+    /// yet. With `allow_guests` unset every login needs a password.
+    pub fn open(path: impl Into<PathBuf>, allow_guests: bool) -> Result<Self, ChatError> {
+        let path = path.into();
+        let accounts: HashMap<String, String> = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let accounts = accounts
+            .into_iter()
+            .map(|(nickname, hash)| (nickname_key(&nickname), (nickname, hash)))
+            .collect();
+
+        Ok(CredentialStore {
+            path,
+            accounts,
+            allow_guests,
+        })
+    }
+
+    pub fn allow_guests(&self) -> bool {
+        self.allow_guests
+    }
+
+    /// The stored password hash for `nickname`, if it is registered.
+    pub fn password_hash(&self, nickname: &str) -> Option<String> {
+        let (_, hash) = self.accounts.get(&nickname_key(nickname))?;
+        Some(hash.clone())
+    }
+
+    /// `nickname` as it was registered, which may differ from it in case.
+    pub fn registered_nickname(&self, nickname: &str) -> Option<String> {
+        let (registered, _) = self.accounts.get(&nickname_key(nickname))?;
+        Some(registered.clone())
+    }
+
+    pub fn register(&mut self, nickname: &str, password_hash: String) -> Result<(), ChatError> {
+        let key = nickname_key(nickname);
+        if self.accounts.contains_key(&key) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Authentication,
+                message: "Nickname is already registered".to_string(),
+            });
+        }
+        self.accounts.insert(key, (nickname.to_string(), password_hash));
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), ChatError> {
+        // Write to a temporary file first so a crash never leaves a torn store
+        let tmp = self.path.with_extension("tmp");
+        let accounts: HashMap<&str, &str> = self
+            .accounts
+            .values()
+            .map(|(nickname, hash)| (nickname.as_str(), hash.as_str()))
+            .collect();
+        fs::write(&tmp, serde_json::to_vec_pretty(&accounts)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Hashes `password` on a blocking task, waiting for a turn if
+/// `MAX_CONCURRENT_HASHES` are already running.
+pub async fn hash(password: String) -> Result<String, ChatError> {
+    let _turn = HASHING.acquire().await.expect("the semaphore is never closed");
+    tokio::task::spawn_blocking(move || hash_password(&password)).await?
+}
+
+/// Checks `password` against `password_hash` on a blocking task, taking
+/// turns as [`hash`] does.
+pub async fn verify(password: String, password_hash: String) -> Result<bool, ChatError> {
+    let _turn = HASHING.acquire().await.expect("the semaphore is never closed");
+    Ok(tokio::task::spawn_blocking(move || verify_password(&password, &password_hash)).await?)
+}
+
+/// Hashes `password` with a fresh random salt. This is deliberately slow, so
+/// prefer [`hash`].
+pub fn hash_password(password: &str) -> Result<String, ChatError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ChatError {
+            kind: ChatErrorKind::Internal,
+            message: format!("Failed to hash password: {}", e),
+        })
+}
+
+/// Checks `password` against a hash produced by [`hash_password`].
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
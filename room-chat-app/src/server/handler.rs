@@ -1,109 +1,1200 @@
-use crate::common::{ChatError, ChatErrorKind, Message};
+use super::client_manager::Outbox;
+use super::config::ServerConfig;
+use super::credentials::{self, CredentialStore};
+use crate::common::codec::FRAME_OVERHEAD;
+use crate::common::protocol::validate_nickname;
+use crate::common::{
+    ChatError, ChatErrorKind, ClientFrame, Command, FrameReader, FrameWriter, Message, Reply, Role,
+    ServerFrame, PROTOCOL_VERSION,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{watch, Mutex};
+
+/// Silence from a client after which the server pings it.
+const PING_AFTER: Duration = Duration::from_secs(30);
+/// Silence from a client after which its connection is dropped, pings and
+/// all.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// How long a connection gets to log in, however many frames it sends.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a new connection gets to send its first frame. A client sends
+/// its `Hello` straight away, a connection that says nothing is dropped
+/// long before `LOGIN_TIMEOUT`.
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections from one address that may be logging in at once. Password
+/// checks are capped by `credentials`, this keeps one address from taking
+/// up the server with idle connections.
+const MAX_LOGINS_PER_ADDRESS: usize = 8;
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Failed logins a connection gets before it is dropped, each costing the
+/// server a password check. Frames other than a `Hello` count as failures.
+const MAX_LOGIN_ATTEMPTS: u32 = 5;
+
+/// Writes `frame`, giving up on a client that has not taken anything for
+/// `IDLE_TIMEOUT`, as a vanished one never will. A frame too large to send
+/// is dropped, the session carries on without it.
+async fn send<W>(writer: &mut FrameWriter<W>, frame: &ServerFrame) -> Result<(), ChatError>
+where
+    W: AsyncWrite + Unpin,
+{
+    match tokio::time::timeout(IDLE_TIMEOUT, writer.write_frame(frame)).await {
+        Ok(Err(e)) if e.kind == ChatErrorKind::Protocol => {
+            eprintln!("Dropped a frame for the client: {}", e.message);
+            Ok(())
+        }
+        Ok(result) => result,
+        Err(_) => Err(ChatError {
+            kind: ChatErrorKind::Connection,
+            message: "Timed out writing to the client".to_string(),
+        }),
+    }
+}
+
+fn login_timed_out() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Connection,
+        message: "Took too long to log in".to_string(),
+    }
+}
+
+/// Runs `future` unless `deadline` passes first.
+async fn by<T>(
+    deadline: tokio::time::Instant,
+    future: impl Future<Output = Result<T, ChatError>>,
+) -> Result<T, ChatError> {
+    tokio::time::timeout_at(deadline, future)
+        .await
+        .unwrap_or_else(|_| Err(login_timed_out()))
+}
+
+/// The connections still logging in, counted per remote address. They do
+/// not count towards `max_clients`, which only counts sessions.
+#[derive(Default)]
+pub struct PendingLogins {
+    counts: std::sync::Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PendingLogins {
+    /// Counts a connection from `ip` as logging in until the returned guard
+    /// is dropped, unless `MAX_LOGINS_PER_ADDRESS` already are.
+    fn start(self: &Arc<Self>, ip: IpAddr) -> Option<LoggingIn> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= MAX_LOGINS_PER_ADDRESS {
+            return None;
+        }
+        *count += 1;
+        Some(LoggingIn {
+            logins: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+/// A connection counted in `PendingLogins`.
+struct LoggingIn {
+    logins: Arc<PendingLogins>,
+    ip: IpAddr,
+}
+
+impl Drop for LoggingIn {
+    fn drop(&mut self) {
+        let mut counts = self.logins.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// How a logged-in session ended.
+enum Departure {
+    Closed,
+    Quit(Option<String>),
+    // Cut off by the registry for falling too far behind
+    Lagged,
+    // Nothing heard from the client for `IDLE_TIMEOUT`
+    TimedOut,
+    ShuttingDown,
+}
+
+/// Resolves once the server starts shutting down.
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    // A server that went away without saying so is shutting down all the same
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
 
 pub struct ClientHandler {
     username: String,
-    room_manager: Arc<Mutex<super::room_manager::RoomManager>>,
+    room_manager: Arc<super::room_manager::RoomManager>,
     client_manager: Arc<Mutex<super::client_manager::ClientManager>>,
-    tx: mpsc::Sender<Message>,
-    rx: mpsc::Receiver<Message>,
+    credentials: Arc<Mutex<CredentialStore>>,
+    config: Arc<ServerConfig>,
+    logins: Arc<PendingLogins>,
+    shutdown: watch::Receiver<bool>,
 }
 
 impl ClientHandler {
-    pub async fn new(
-        username: String,
-        room_manager: Arc<Mutex<super::room_manager::RoomManager>>,
+    pub fn new(
+        room_manager: Arc<super::room_manager::RoomManager>,
         client_manager: Arc<Mutex<super::client_manager::ClientManager>>,
-    ) -> Result<Self, ChatError> {
-        let (tx, rx) = mpsc::channel(100);
-        
-        // Register client
-        client_manager.lock().await.add_client(username.clone(), tx.clone()).await?;
-    
-        Ok(ClientHandler {
-            username,
+        credentials: Arc<Mutex<CredentialStore>>,
+        config: Arc<ServerConfig>,
+        logins: Arc<PendingLogins>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        ClientHandler {
+            username: String::new(),
             room_manager,
             client_manager,
-            tx,
-            rx,
-        })
+            credentials,
+            config,
+            logins,
+            shutdown,
+        }
+    }
+
+    pub async fn handle(self, mut stream: TcpStream) -> Result<(), ChatError> {
+        let addr = stream.peer_addr()?;
+        let (reader, writer) = stream.split();
+        self.run(reader, writer, addr).await
+    }
+
+    async fn run<R, W>(mut self, reader: R, writer: W, addr: SocketAddr) -> Result<(), ChatError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let max_frame_size = self.config.max_message_size;
+        let mut reader = FrameReader::new(reader).with_max_frame_size(max_frame_size);
+        // Room for what the server wraps around text it relays
+        let mut writer =
+            FrameWriter::new(writer).with_max_frame_size(max_frame_size + FRAME_OVERHEAD);
+
+        let Some(logging_in) = self.logins.start(addr.ip()) else {
+            let message = "Too many connections logging in from your address".to_string();
+            let code = ChatErrorKind::Connection;
+            send(&mut writer, &ServerFrame::Error { code, message }).await?;
+            return Ok(());
+        };
+        let login = self.login(&mut reader, &mut writer, addr).await;
+        drop(logging_in);
+        let Some(mut outbox) = login? else {
+            return Ok(());
+        };
+
+        // Whatever ended the session, its rooms are told and its nickname
+        // freed before the error, if any, is passed on. That includes failing
+        // to send the welcome, the nickname is taken from here on.
+        let result = match self.greet(&mut writer).await {
+            Ok(()) => self.serve(&mut reader, &mut writer, &mut outbox).await,
+            Err(e) => Err(e),
+        };
+        let farewell = match &result {
+            Ok(Departure::Quit(Some(reason))) => format!("{} quit: {}", self.username, reason),
+            Ok(Departure::Quit(None)) => format!("{} quit", self.username),
+            Ok(Departure::Lagged) => format!("{} left (too far behind)", self.username),
+            Ok(Departure::TimedOut) => format!("{} left (timed out)", self.username),
+            Ok(Departure::ShuttingDown) => {
+                format!("{} left (server shutting down)", self.username)
+            }
+            Ok(Departure::Closed) | Err(_) => {
+                format!("{} left (connection closed)", self.username)
+            }
+        };
+        self.cleanup(&farewell).await?;
+
+        result.map(|_| ())
     }
 
-    pub async fn handle(mut self, mut stream: TcpStream) -> Result<(), ChatError> {
-        let (mut reader, mut writer) = stream.split();
-        let mut buf = [0u8; 1024];
+    /// Welcomes a session that just logged in, then hands it the private
+    /// messages it was sent while offline and the message of the day. Those
+    /// messages it never received are kept for its next login.
+    async fn greet<W>(&mut self, writer: &mut FrameWriter<W>) -> Result<(), ChatError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let welcome = ServerFrame::Welcome {
+            version: PROTOCOL_VERSION,
+            nickname: self.username.clone(),
+        };
+        send(writer, &welcome).await?;
+
+        let mut pending = self
+            .client_manager
+            .lock()
+            .await
+            .take_pending(&self.username)
+            .into_iter();
+        while let Some(message) = pending.next() {
+            if let Err(e) = send(writer, &ServerFrame::Private(message.clone())).await {
+                let unsent = std::iter::once(message).chain(pending).collect();
+                self.client_manager
+                    .lock()
+                    .await
+                    .restore_pending(&self.username, unsent);
+                return Err(e);
+            }
+        }
+        if let Some(motd) = &self.config.motd {
+            let motd = Message::new(String::new(), "System".to_string(), motd.clone());
+            send(writer, &ServerFrame::System(motd)).await?;
+        }
+        Ok(())
+    }
+
+    async fn serve<R, W>(
+        &mut self,
+        reader: &mut FrameReader<R>,
+        writer: &mut FrameWriter<W>,
+        outbox: &mut Outbox,
+    ) -> Result<Departure, ChatError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut last_heard = Instant::now();
+        let mut pinged = false;
+        let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
 
         loop {
             tokio::select! {
-                result = reader.read(&mut buf) => {
+                result = reader.read_frame::<ClientFrame>() => {
+                    last_heard = Instant::now();
+                    pinged = false;
                     match result {
-                        Ok(0) => break, // Connection closed
-                        Ok(n) => {
-                            let msg: Message = serde_json::from_slice(&buf[..n])?;
-                            if msg.content.starts_with('/') {
-                                self.handle_command(&msg.content).await?;
-                            } else {
-                                let mut room_manager = self.room_manager.lock().await;
-                                room_manager.broadcast_message(msg).await?;
+                        Ok(None) => return Ok(Departure::Closed),
+                        Ok(Some(ClientFrame::Chat(msg))) => {
+                            // Sender, id and timestamp are the server's to assign, history
+                            // paging relies on the ids
+                            let msg = Message::new(msg.room, self.username.clone(), msg.content);
+                            let result = match self.check_length(&msg.content) {
+                                Ok(()) => self.room_manager.broadcast_message(msg).await,
+                                Err(e) => Err(e),
+                            };
+                            if let Err(e) = result {
+                                send(writer, &ServerFrame::from(e)).await?;
                             }
                         }
-                        Err(e) => return Err(e.into()),
+                        Ok(Some(ClientFrame::Command(Command::Quit(reason)))) => {
+                            return Ok(Departure::Quit(reason));
+                        }
+                        Ok(Some(ClientFrame::Command(command))) => {
+                            // A failed command is the client's to hear about, only
+                            // errors on the socket itself end the session
+                            match self.handle_command(command).await {
+                                Ok(Some(reply)) => {
+                                    send(writer, &ServerFrame::Reply(reply)).await?;
+                                }
+                                Ok(None) => {}
+                                Err(e) => send(writer, &ServerFrame::from(e)).await?,
+                            }
+                        }
+                        Ok(Some(ClientFrame::Ping)) => {
+                            send(writer, &ServerFrame::Pong).await?;
+                        }
+                        Ok(Some(ClientFrame::Pong)) => {}
+                        Ok(Some(ClientFrame::Hello { .. })) => {
+                            let message = format!("Already logged in as {}", self.username);
+                            let code = ChatErrorKind::Protocol;
+                            send(writer, &ServerFrame::Error { code, message }).await?;
+                        }
+                        Err(e) if !e.is_fatal() => {
+                            // The codec has already skipped the oversized or malformed
+                            // frame, tell the client and carry on
+                            send(writer, &ServerFrame::from(e)).await?;
+                        }
+                        Err(e) => return Err(e),
                     }
                 }
-                Some(msg) = self.rx.recv() => {
-                    let data = serde_json::to_vec(&msg)?;
-                    writer.write_all(&data).await?;
+                frame = outbox.recv() => {
+                    let Some(frame) = frame else {
+                        let frame = ServerFrame::Error {
+                            code: ChatErrorKind::Connection,
+                            message: "Disconnected for falling too far behind".to_string(),
+                        };
+                        send(writer, &frame).await?;
+                        return Ok(Departure::Lagged);
+                    };
+                    send(writer, &frame).await?;
+                }
+                _ = shutting_down(&mut self.shutdown) => {
+                    let notice = Message::new(
+                        String::new(),
+                        "System".to_string(),
+                        "Server shutting down".to_string(),
+                    );
+                    send(writer, &ServerFrame::System(notice)).await?;
+                    return Ok(Departure::ShuttingDown);
+                }
+                _ = idle_check.tick() => {
+                    let silent = last_heard.elapsed();
+                    if silent >= IDLE_TIMEOUT {
+                        let frame = ServerFrame::Error {
+                            code: ChatErrorKind::Connection,
+                            message: "Disconnected after being idle too long".to_string(),
+                        };
+                        send(writer, &frame).await?;
+                        return Ok(Departure::TimedOut);
+                    }
+                    if silent >= PING_AFTER && !pinged {
+                        send(writer, &ServerFrame::Ping).await?;
+                        pinged = true;
+                    }
                 }
             }
         }
+    }
 
-        // Cleanup
-        let mut client_manager = self.client_manager.lock().await;
-        client_manager.remove_client(&self.username).await?;
-
+    /// Rejects text that would not fit in a frame once wrapped as a message
+    /// from this server, counting it as encoded on the wire.
+    fn check_length(&self, text: &str) -> Result<(), ChatError> {
+        let max = self.config.max_message_size - FRAME_OVERHEAD;
+        if serde_json::to_string(text)?.len() > max {
+            return Err(ChatError {
+                kind: ChatErrorKind::Message,
+                message: format!("Message too long, the limit is {} bytes", max),
+            });
+        }
         Ok(())
     }
 
-    async fn handle_command(&mut self, cmd_str: &str) -> Result<(), ChatError> {
-        let parts: Vec<&str> = cmd_str.split_whitespace().collect();
-        match parts.get(0) {
-            Some(&"/join") => {
-                if let Some(&room) = parts.get(1) {
-                    let mut room_manager = self.room_manager.lock().await;
-                    room_manager.join_room(&self.username, room).await?;
-                } else {
-                    return Err(ChatError {
-                        kind: ChatErrorKind::Command,
-                        message: "Usage: /join <room>".to_string(),
-                    });
+    /// `user` spelled the way they registered or are logged in, so that a
+    /// role, ban or invitation lands on the user meant. Names nobody has are
+    /// refused rather than reserved for whoever takes them next.
+    async fn known_user(&self, user: &str) -> Result<String, ChatError> {
+        if let Some(registered) = self.credentials.lock().await.registered_nickname(user) {
+            return Ok(registered);
+        }
+        match self.client_manager.lock().await.session(user) {
+            Some(session) => Ok(session.nickname.clone()),
+            None => Err(ChatError {
+                kind: ChatErrorKind::Command,
+                message: format!("No user named {}", user),
+            }),
+        }
+    }
+
+    /// `user` spelled the way they registered. Guests can take any free
+    /// nickname, so a role or invitation given to one would pass on to
+    /// whoever uses it next.
+    async fn registered_user(&self, user: &str) -> Result<String, ChatError> {
+        match self.credentials.lock().await.registered_nickname(user) {
+            Some(registered) => Ok(registered),
+            None => Err(ChatError {
+                kind: ChatErrorKind::Permission,
+                message: format!("{} is not a registered nickname", user),
+            }),
+        }
+    }
+
+    /// Keeps the most recent of `messages`, oldest first, that fit in one
+    /// frame with `FRAME_OVERHEAD` to spare for the reply around them. The
+    /// older ones are still there to page back to with `/history`.
+    fn fit_in_frame(&self, mut messages: Vec<Message>) -> Vec<Message> {
+        let mut room_left = self.config.max_message_size;
+        let fitting = messages
+            .iter()
+            .rev()
+            .take_while(|message| {
+                // Each message also takes a comma in the list
+                let size = serde_json::to_vec(message).map_or(usize::MAX, |v| v.len() + 1);
+                match room_left.checked_sub(size) {
+                    Some(left) => {
+                        room_left = left;
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .count();
+        messages.drain(..messages.len() - fitting);
+        messages
+    }
+
+    /// Frees the nickname and takes the user out of every room they were in,
+    /// telling those rooms `farewell`.
+    async fn cleanup(&mut self, farewell: &str) -> Result<(), ChatError> {
+        // Unregister first so nothing more is queued for this connection
+        let session = self
+            .client_manager
+            .lock()
+            .await
+            .remove_client(&self.username)
+            .await?;
+        self.room_manager
+            .disconnect(&self.username, session.rooms, farewell)
+            .await
+    }
+
+    /// Waits for a `Hello` frame and registers the proposed nickname. A taken
+    /// or invalid nickname is reported back and the client may try again, up
+    /// to `MAX_LOGIN_ATTEMPTS` times. Returns the session's outbox, or `None`
+    /// if the connection ended before a successful login. The session is not
+    /// welcomed yet, see `greet`.
+    async fn login<R, W>(
+        &mut self,
+        reader: &mut FrameReader<R>,
+        writer: &mut FrameWriter<W>,
+        addr: SocketAddr,
+    ) -> Result<Option<Outbox>, ChatError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        // One deadline for the whole login, a client cannot buy more time by
+        // pinging or failing
+        let deadline = tokio::time::Instant::now() + LOGIN_TIMEOUT;
+        let mut first_frame = Some(tokio::time::Instant::now() + FIRST_FRAME_TIMEOUT);
+        let mut failures = 0;
+        loop {
+            let by_then = first_frame.take().map_or(deadline, |first| first.min(deadline));
+            let frame = tokio::select! {
+                frame = by(by_then, reader.read_frame()) => frame,
+                _ = shutting_down(&mut self.shutdown) => return Ok(None),
+            };
+            let attempt = match frame {
+                Ok(Some(ClientFrame::Hello {
+                    version,
+                    nickname,
+                    password,
+                })) => {
+                    if version != PROTOCOL_VERSION {
+                        let message = format!(
+                            "Unsupported protocol version {}, server speaks version {}",
+                            version, PROTOCOL_VERSION
+                        );
+                        let code = ChatErrorKind::Protocol;
+                        by(deadline, send(writer, &ServerFrame::Error { code, message })).await?;
+                        return Ok(None);
+                    }
+                    self.try_login(&nickname, password, addr, deadline).await
+                }
+                Ok(Some(ClientFrame::Ping)) => {
+                    by(deadline, send(writer, &ServerFrame::Pong)).await?;
+                    continue;
+                }
+                Ok(Some(ClientFrame::Pong)) => continue,
+                Ok(Some(_)) => Err(ChatError {
+                    kind: ChatErrorKind::Protocol,
+                    message: "Log in with a Hello frame first".to_string(),
+                }),
+                Ok(None) => return Ok(None),
+                // The codec skipped the frame, the client may try again
+                Err(e) if !e.is_fatal() => Err(e),
+                Err(e) => return Err(e),
+            };
+
+            match attempt {
+                Ok((nickname, outbox)) => {
+                    self.username = nickname;
+                    return Ok(Some(outbox));
+                }
+                Err(e) => {
+                    by(deadline, send(writer, &ServerFrame::from(e))).await?;
+                    failures += 1;
+                    if failures >= MAX_LOGIN_ATTEMPTS {
+                        let message = "Too many failed logins".to_string();
+                        let code = ChatErrorKind::Authentication;
+                        by(deadline, send(writer, &ServerFrame::Error { code, message })).await?;
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Logs in as `nickname`, returning the nickname the session goes by and
+    /// its outbox.
+    async fn try_login(
+        &mut self,
+        nickname: &str,
+        password: Option<String>,
+        addr: SocketAddr,
+        deadline: tokio::time::Instant,
+    ) -> Result<(String, Outbox), ChatError> {
+        validate_nickname(nickname)?;
+        // A registered nickname is always spelled as it was registered,
+        // however its case is given
+        let nickname = self
+            .credentials
+            .lock()
+            .await
+            .registered_nickname(nickname)
+            .unwrap_or_else(|| nickname.to_string());
+        let nickname = nickname.as_str();
+        // Spares a password check, the session below is what holds the name
+        if self.client_manager.lock().await.is_online(nickname) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Authentication,
+                message: "Username already taken".to_string(),
+            });
+        }
+        let registration = by(deadline, self.authenticate(nickname, password)).await?;
+        // Not cut short by the deadline from here on, or the session could be
+        // left registered with no connection handler to remove it
+        let outbox = self
+            .client_manager
+            .lock()
+            .await
+            .add_client(nickname.to_string(), addr)
+            .await?;
+
+        // A free nickname is only registered once its session is secured, so
+        // a connected guest's nickname cannot be claimed from under them
+        if let Some(hash) = registration {
+            // Bound first so the store is unlocked before the registry is
+            // locked, the two are never held at once
+            let registered = self.credentials.lock().await.register(nickname, hash);
+            if let Err(e) = registered {
+                self.client_manager.lock().await.remove_client(nickname).await?;
+                return Err(e);
+            }
+        }
+        Ok((nickname.to_string(), outbox))
+    }
+
+    /// Checks `password` against the nickname's registration. For a free
+    /// nickname given a password, returns the hash to register it with once
+    /// the session is set up.
+    async fn authenticate(
+        &self,
+        nickname: &str,
+        password: Option<String>,
+    ) -> Result<Option<String>, ChatError> {
+        let (password_hash, allow_guests) = {
+            let credentials = self.credentials.lock().await;
+            (credentials.password_hash(nickname), credentials.allow_guests())
+        };
+
+        let message = match (password_hash, password) {
+            (Some(hash), Some(password)) => {
+                if credentials::verify(password, hash).await? {
+                    return Ok(None);
                 }
+                "Invalid password"
+            }
+            (Some(_), None) => "Nickname is registered, a password is required",
+            (None, Some(password)) => {
+                return Ok(Some(credentials::hash(password).await?));
             }
-            Some(&"/leave") => {
-                if let Some(&room) = parts.get(1) {
-                    let mut room_manager = self.room_manager.lock().await;
-                    room_manager.leave_room(&self.username, room).await?;
-                } else {
+            (None, None) if allow_guests => return Ok(None),
+            (None, None) => "Guest logins are disabled, log in with a password to register",
+        };
+        Err(ChatError {
+            kind: ChatErrorKind::Authentication,
+            message: message.to_string(),
+        })
+    }
+
+    async fn register(&self, nickname: &str, password: String) -> Result<(), ChatError> {
+        let hash = credentials::hash(password).await?;
+        self.credentials.lock().await.register(nickname, hash)
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<Option<Reply>, ChatError> {
+        let reply = match command {
+            Command::Join { room, password } => {
+                let history = self
+                    .room_manager
+                    .join_room(&self.username, &room, password)
+                    .await?;
+                // The client keeps the room under the name it goes by
+                let room = self.room_manager.room_name(&room).await.unwrap_or(room);
+                let history = self.fit_in_frame(history);
+                Reply::Joined { room, history }
+            }
+            Command::Leave(room) => {
+                self.room_manager.leave_room(&self.username, &room).await?;
+                Reply::Left(room)
+            }
+            Command::ListRooms => {
+                Reply::Rooms(self.room_manager.list_rooms(&self.username).await)
+            }
+            Command::ListUsers(room) => {
+                let users = self.room_manager.list_users(&self.username, &room).await?;
+                Reply::Users { room, users }
+            }
+            Command::History { room, limit, before } => {
+                let messages = self
+                    .room_manager
+                    .history(&self.username, &room, limit, before)
+                    .await?;
+                let messages = self.fit_in_frame(messages);
+                Reply::History { room, messages }
+            }
+            Command::Create(room) => {
+                // Ownership is kept with the room, see `registered_user`
+                if self.credentials.lock().await.password_hash(&self.username).is_none() {
                     return Err(ChatError {
-                        kind: ChatErrorKind::Command,
-                        message: "Usage: /leave <room>".to_string(),
+                        kind: ChatErrorKind::Permission,
+                        message: "Register your nickname to create rooms".to_string(),
                     });
                 }
+                self.room_manager
+                    .create_room(room.clone(), Some(self.username.clone()))
+                    .await?;
+                Reply::Created(room)
             }
-            Some(&"/quit") => {
+            Command::Rename { room, new_name } => {
+                self.room_manager
+                    .rename_room(&self.username, &room, new_name.clone())
+                    .await?;
+                Reply::Renamed { room, new_name }
+            }
+            Command::Delete(room) => {
+                self.room_manager.delete_room(&self.username, &room).await?;
+                Reply::Deleted(room)
+            }
+            Command::Op { room, user } => {
+                let user = self.registered_user(&user).await?;
+                self.room_manager
+                    .set_role(&self.username, &room, &user, Role::Moderator)
+                    .await?;
+                Reply::RoleChanged {
+                    room,
+                    user,
+                    role: Role::Moderator,
+                }
+            }
+            Command::Deop { room, user } => {
+                self.room_manager
+                    .set_role(&self.username, &room, &user, Role::Member)
+                    .await?;
+                Reply::RoleChanged {
+                    room,
+                    user,
+                    role: Role::Member,
+                }
+            }
+            Command::Kick { room, user, reason } => {
+                self.room_manager
+                    .kick(&self.username, &room, &user, reason)
+                    .await?;
+                Reply::Kicked { room, user }
+            }
+            Command::Ban {
+                room,
+                user,
+                duration,
+            } => {
+                let user = self.known_user(&user).await?;
+                let duration = duration.map(Duration::from_secs);
+                self.room_manager
+                    .ban(&self.username, &room, &user, duration)
+                    .await?;
+                Reply::Banned { room, user }
+            }
+            Command::Unban { room, user } => {
+                self.room_manager.unban(&self.username, &room, &user).await?;
+                Reply::Unbanned { room, user }
+            }
+            Command::Invite { room, user } => {
+                let user = self.registered_user(&user).await?;
+                self.room_manager.invite(&self.username, &room, &user).await?;
+                Reply::Invited { room, user }
+            }
+            Command::Mode { room, change } => {
+                self.room_manager
+                    .set_mode(&self.username, &room, change.clone())
+                    .await?;
+                Reply::ModeChanged { room, change }
+            }
+            Command::Msg { user, text } => {
+                self.check_length(&text)?;
+                let message = Message::new(user.clone(), self.username.clone(), text);
+                // Only registered users are sure to come back under the same
+                // name. Looked up before the registry is locked, see `try_login`.
+                let registered = self.credentials.lock().await.password_hash(&user).is_some();
                 let mut client_manager = self.client_manager.lock().await;
-                client_manager.remove_client(&self.username).await?;
+                let queued = !client_manager.send_private(message.clone());
+                if queued {
+                    if !registered {
+                        return Err(ChatError {
+                            kind: ChatErrorKind::Message,
+                            message: format!("{} is offline", user),
+                        });
+                    }
+                    client_manager.queue_private(message.clone())?;
+                }
+                Reply::PrivateSent { message, queued }
             }
-            _ => {
-                return Err(ChatError {
-                    kind: ChatErrorKind::Command,
-                    message: "Unknown command".to_string(),
-                });
+            Command::Register(password) => {
+                self.register(&self.username, password).await?;
+                Reply::Registered(self.username.clone())
+            }
+            // Quitting ends the connection loop, there is nothing to reply
+            Command::Quit(_) => return Ok(None),
+        };
+        Ok(Some(reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::client_manager::{self, ClientManager};
+    use crate::server::room_manager::RoomManager;
+    use tokio::io::duplex;
+
+    /// What the connection handlers of one test share. Its credentials are
+    /// kept in a file of its own, removed once the test is done.
+    struct TestServer {
+        clients: Arc<Mutex<ClientManager>>,
+        rooms: Arc<RoomManager>,
+        credentials: Arc<Mutex<CredentialStore>>,
+        credentials_path: std::path::PathBuf,
+        logins: Arc<PendingLogins>,
+        stop: watch::Sender<bool>,
+    }
+
+    impl TestServer {
+        fn new(test: &str) -> Self {
+            let file = format!("room-chat-{}-{}.json", test, std::process::id());
+            let credentials_path = std::env::temp_dir().join(file);
+            let _ = std::fs::remove_file(&credentials_path);
+            let credentials = CredentialStore::open(&credentials_path, true).unwrap();
+            let clients = Arc::new(Mutex::new(ClientManager::new()));
+            TestServer {
+                rooms: Arc::new(RoomManager::new(Arc::clone(&clients))),
+                clients,
+                credentials: Arc::new(Mutex::new(credentials)),
+                credentials_path,
+                logins: Arc::new(PendingLogins::default()),
+                stop: watch::channel(false).0,
             }
         }
-        Ok(())
+
+        fn handler(&self) -> ClientHandler {
+            ClientHandler::new(
+                Arc::clone(&self.rooms),
+                Arc::clone(&self.clients),
+                Arc::clone(&self.credentials),
+                Arc::new(ServerConfig::default()),
+                Arc::clone(&self.logins),
+                self.stop.subscribe(),
+            )
+        }
+    }
+
+    impl TestServer {
+        /// A handler for `nickname`'s session, as if they had logged in.
+        fn session(&self, nickname: &str) -> ClientHandler {
+            let mut handler = self.handler();
+            handler.username = nickname.to_string();
+            handler
+        }
+
+        async fn register(&self, nickname: &str, password: &str) {
+            let hash = credentials::hash(password.to_string()).await.unwrap();
+            self.credentials.lock().await.register(nickname, hash).unwrap();
+        }
+
+        /// Connects from `addr`, sends `frames` and hangs up, returning every
+        /// frame the server sent back.
+        async fn connect(&self, frames: &[ClientFrame], addr: SocketAddr) -> Vec<ServerFrame> {
+            let (server_reader, client_writer) = duplex(64 * 1024);
+            let (client_reader, server_writer) = duplex(64 * 1024);
+            let mut client_writer = FrameWriter::new(client_writer);
+            for frame in frames {
+                client_writer.write_frame(frame).await.unwrap();
+            }
+            drop(client_writer);
+            self.handler().run(server_reader, server_writer, addr).await.unwrap();
+
+            let mut client_reader = FrameReader::new(client_reader);
+            let mut received = Vec::new();
+            while let Some(frame) = client_reader.read_frame().await.unwrap() {
+                received.push(frame);
+            }
+            received
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.credentials_path);
+        }
+    }
+
+    fn hello(nickname: &str) -> ClientFrame {
+        ClientFrame::Hello {
+            version: PROTOCOL_VERSION,
+            nickname: nickname.to_string(),
+            password: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn frees_the_nickname_when_the_connection_drops_during_login() {
+        let server = TestServer::new("dropped-during-login");
+        let clients = &server.clients;
+        let pending = Message::new("ghost".to_string(), "alice".to_string(), "hi".to_string());
+        clients.lock().await.queue_private(pending).unwrap();
+
+        // The client says hello and is gone before it can be welcomed
+        let (server_end, client) = duplex(1024);
+        let mut client = FrameWriter::new(client);
+        client.write_frame(&hello("ghost")).await.unwrap();
+        drop(client);
+        let (reader, writer) = tokio::io::split(server_end);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let result = server.handler().run(reader, writer, addr).await;
+        assert!(result.unwrap_err().is_fatal());
+
+        let mut clients = clients.lock().await;
+        assert!(!clients.is_online("ghost"));
+        let pending = clients.take_pending("ghost");
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].content, "hi");
+    }
+
+    #[tokio::test]
+    async fn counts_frames_other_than_hello_as_failed_logins() {
+        let server = TestServer::new("stray-login-frames");
+        let clients = &server.clients;
+        let (server_end, client) = duplex(64 * 1024);
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let mut client_writer = FrameWriter::new(client_writer);
+        for _ in 0..MAX_LOGIN_ATTEMPTS {
+            let frame = ClientFrame::Command(Command::ListRooms);
+            client_writer.write_frame(&frame).await.unwrap();
+            client_writer.write_frame(&ClientFrame::Ping).await.unwrap();
+        }
+        client_writer.write_frame(&hello("eve")).await.unwrap();
+
+        let (reader, writer) = tokio::io::split(server_end);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        server.handler().run(reader, writer, addr).await.unwrap();
+        assert!(!clients.lock().await.is_online("eve"));
+
+        let mut client_reader = FrameReader::new(client_reader);
+        let mut last = None;
+        while let Some(frame) = client_reader.read_frame::<ServerFrame>().await.unwrap() {
+            last = Some(frame);
+        }
+        match last {
+            Some(ServerFrame::Error { message, .. }) => {
+                assert_eq!(message, "Too many failed logins")
+            }
+            _ => panic!("expected the login to be refused"),
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_nicknames_passing_for_a_connected_one() {
+        let server = TestServer::new("lookalike-nicknames");
+        let clients = &server.clients;
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let _carol = clients.lock().await.add_client("carol".to_string(), addr).await;
+
+        let (server_reader, client_writer) = duplex(64 * 1024);
+        let (client_reader, server_writer) = duplex(64 * 1024);
+        let mut client_writer = FrameWriter::new(client_writer);
+        // The second has a Cyrillic "а"
+        for nickname in ["Carol", "c\u{430}rol"] {
+            client_writer.write_frame(&hello(nickname)).await.unwrap();
+        }
+        drop(client_writer);
+        server.handler().run(server_reader, server_writer, addr).await.unwrap();
+
+        let mut client_reader = FrameReader::new(client_reader);
+        let mut refusals = Vec::new();
+        while let Some(frame) = client_reader.read_frame::<ServerFrame>().await.unwrap() {
+            match frame {
+                ServerFrame::Error { message, .. } => refusals.push(message),
+                _ => panic!("expected both logins to be refused"),
+            }
+        }
+        assert_eq!(refusals.len(), 2);
+        assert_eq!(refusals[0], "Username already taken");
+        assert!(refusals[1].contains("ASCII"));
+    }
+    fn msg(user: &str, text: &str) -> Command {
+        Command::Msg {
+            user: user.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_private_messages_for_registered_users_until_they_log_in() {
+        let server = TestServer::new("offline-messages");
+        server.register("bob", "hunter2").await;
+        let mut alice = server.session("alice");
+        for text in ["first", "second"] {
+            match alice.handle_command(msg("Bob", text)).await.unwrap() {
+                Some(Reply::PrivateSent { queued, .. }) => assert!(queued),
+                _ => panic!("expected the message to be queued"),
+            }
+        }
+        // Guests may not come back as who they were, so nothing is kept
+        let error = alice.handle_command(msg("carol", "hi")).await.unwrap_err();
+        assert_eq!(error.message, "carol is offline");
+
+        let hello = ClientFrame::Hello {
+            version: PROTOCOL_VERSION,
+            nickname: "bob".to_string(),
+            password: Some("hunter2".to_string()),
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let frames = server.connect(&[hello], addr).await;
+        assert!(matches!(frames[0], ServerFrame::Welcome { .. }));
+        let delivered: Vec<_> = frames[1..]
+            .iter()
+            .map(|frame| match frame {
+                ServerFrame::Private(message) => {
+                    assert_eq!(message.sender, "alice");
+                    message.content.as_str()
+                }
+                _ => panic!("expected the queued messages"),
+            })
+            .collect();
+        assert_eq!(delivered, ["first", "second"]);
+        assert!(server.clients.lock().await.take_pending("bob").is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_queueing_for_a_user_with_too_many_waiting() {
+        let server = TestServer::new("offline-queue-cap");
+        // Registered is all it takes, the password is never checked
+        let mut credentials = server.credentials.lock().await;
+        credentials.register("bob", "hash".to_string()).unwrap();
+        drop(credentials);
+        let mut alice = server.session("alice");
+        for i in 0..client_manager::MAX_PENDING {
+            alice.handle_command(msg("bob", &i.to_string())).await.unwrap();
+        }
+        let error = alice.handle_command(msg("bob", "one more")).await.unwrap_err();
+        assert_eq!(error.message, "bob has too many messages waiting");
+
+        let pending = server.clients.lock().await.take_pending("bob");
+        assert_eq!(pending.len(), client_manager::MAX_PENDING);
+        assert_eq!(pending[0].content, "0");
+    }
+
+    #[tokio::test]
+    async fn leaves_every_room_on_quit_or_a_dropped_connection() {
+        let server = TestServer::new("leave-on-disconnect");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let bob = server.clients.lock().await.add_client("bob".to_string(), addr).await;
+        let mut bob = bob.unwrap();
+        for room in ["lobby", "hall"] {
+            server.rooms.create_room(room.to_string(), None).await.unwrap();
+            server.rooms.join_room("bob", room, None).await.unwrap();
+        }
+
+        let quit = ClientFrame::Command(Command::Quit(Some("gone fishing".to_string())));
+        let cases = [
+            ("alice", Some(quit), "alice quit: gone fishing"),
+            ("carol", None, "carol left (connection closed)"),
+        ];
+        for (nickname, last, farewell) in cases {
+            let mut frames = vec![hello(nickname)];
+            for room in ["lobby", "hall"] {
+                frames.push(ClientFrame::Command(Command::Join {
+                    room: room.to_string(),
+                    password: None,
+                }));
+            }
+            frames.extend(last);
+            server.connect(&frames, addr).await;
+            assert!(!server.clients.lock().await.is_online(nickname));
+
+            // Bob hears of it in both rooms
+            let mut told = Vec::new();
+            let wait = Duration::from_millis(50);
+            while let Ok(Some(frame)) = tokio::time::timeout(wait, bob.recv()).await {
+                if let ServerFrame::System(notice) = frame {
+                    if notice.content == farewell {
+                        told.push(notice.room);
+                    }
+                }
+            }
+            told.sort();
+            assert_eq!(told, ["hall", "lobby"]);
+            for room in ["lobby", "hall"] {
+                assert_eq!(server.rooms.list_users("bob", room).await.unwrap(), ["bob"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_clients_the_server_is_shutting_down() {
+        let server = TestServer::new("shutdown");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let bob = server.clients.lock().await.add_client("bob".to_string(), addr).await;
+        let mut bob = bob.unwrap();
+        server.rooms.create_room("lobby".to_string(), None).await.unwrap();
+        server.rooms.join_room("bob", "lobby", None).await.unwrap();
+
+        // One connection logged in and in the lobby, one yet to say hello
+        let (server_reader, client_writer) = duplex(64 * 1024);
+        let (client_reader, server_writer) = duplex(64 * 1024);
+        let alice = tokio::spawn(server.handler().run(server_reader, server_writer, addr));
+        let mut client_writer = FrameWriter::new(client_writer);
+        client_writer.write_frame(&hello("alice")).await.unwrap();
+        let join = Command::Join {
+            room: "lobby".to_string(),
+            password: None,
+        };
+        client_writer.write_frame(&ClientFrame::Command(join)).await.unwrap();
+        let mut client_reader = FrameReader::new(client_reader);
+        loop {
+            let frame = client_reader.read_frame::<ServerFrame>().await.unwrap();
+            if let Some(ServerFrame::Reply(Reply::Joined { .. })) = frame {
+                break;
+            }
+        }
+        let (silent_reader, _silent_client) = duplex(1024);
+        let (_, silent_writer) = duplex(1024);
+        let silent = tokio::spawn(server.handler().run(silent_reader, silent_writer, addr));
+
+        server.stop.send_replace(true);
+        alice.await.unwrap().unwrap();
+        silent.await.unwrap().unwrap();
+
+        let mut told = false;
+        while let Some(frame) = client_reader.read_frame::<ServerFrame>().await.unwrap() {
+            if let ServerFrame::System(notice) = frame {
+                told |= notice.content == "Server shutting down";
+            }
+        }
+        assert!(told);
+        let mut farewell = None;
+        let wait = Duration::from_millis(50);
+        while let Ok(Some(frame)) = tokio::time::timeout(wait, bob.recv()).await {
+            if let ServerFrame::System(notice) = frame {
+                farewell = Some(notice.content);
+            }
+        }
+        assert_eq!(farewell.as_deref(), Some("alice left (server shutting down)"));
+        assert!(!server.clients.lock().await.is_online("alice"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn caps_logins_per_address() {
+        let server = TestServer::new("logins-per-address");
+        let busy = SocketAddr::from(([192, 0, 2, 1], 4000));
+        let _idle: Vec<_> = (0..MAX_LOGINS_PER_ADDRESS)
+            .map(|_| server.logins.start(busy.ip()).unwrap())
+            .collect();
+
+        let other = SocketAddr::from(([192, 0, 2, 2], 4000));
+        for (addr, nickname) in [(busy, "dave"), (other, "erin")] {
+            let (server_reader, client_writer) = duplex(64 * 1024);
+            let (client_reader, server_writer) = duplex(64 * 1024);
+            let mut client_writer = FrameWriter::new(client_writer);
+            client_writer.write_frame(&hello(nickname)).await.unwrap();
+            drop(client_writer);
+            server.handler().run(server_reader, server_writer, addr).await.unwrap();
+
+            let mut client_reader = FrameReader::new(client_reader);
+            let first = client_reader.read_frame::<ServerFrame>().await.unwrap();
+            match (nickname, first) {
+                ("dave", Some(ServerFrame::Error { message, .. })) => {
+                    assert_eq!(message, "Too many connections logging in from your address")
+                }
+                ("erin", Some(ServerFrame::Welcome { .. })) => {}
+                (_, frame) => panic!("unexpected first frame {:?}", frame),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_roles_and_bans_for_unknown_users() {
+        let server = TestServer::new("unknown-targets");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let _bob = server.clients.lock().await.add_client("Bob".to_string(), addr).await;
+        {
+            let mut credentials = server.credentials.lock().await;
+            credentials.register("alice", "hash".to_string()).unwrap();
+            credentials.register("Bob", "hash".to_string()).unwrap();
+        }
+        let mut handler = server.handler();
+        handler.username = "alice".to_string();
+        let create = Command::Create("lobby".to_string());
+        handler.handle_command(create).await.unwrap();
+
+        let op = Command::Op {
+            room: "lobby".to_string(),
+            user: "BOB".to_string(),
+        };
+        match handler.handle_command(op).await.unwrap() {
+            Some(Reply::RoleChanged { user, .. }) => assert_eq!(user, "Bob"),
+            _ => panic!("expected Bob to be made a moderator"),
+        }
+        let commands = [
+            Command::Op {
+                room: "lobby".to_string(),
+                user: "nobody".to_string(),
+            },
+            Command::Invite {
+                room: "lobby".to_string(),
+                user: "nobody".to_string(),
+            },
+        ];
+        for command in commands {
+            let error = handler.handle_command(command).await.unwrap_err();
+            assert_eq!(error.message, "nobody is not a registered nickname");
+        }
+        let ban = Command::Ban {
+            room: "lobby".to_string(),
+            user: "nobody".to_string(),
+            duration: None,
+        };
+        let error = handler.handle_command(ban).await.unwrap_err();
+        assert_eq!(error.message, "No user named nobody");
+    }
+
+    #[tokio::test]
+    async fn keeps_room_roles_for_registered_nicknames() {
+        let server = TestServer::new("guest-roles");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let _dave = server.clients.lock().await.add_client("dave".to_string(), addr).await;
+        let mut guest = server.handler();
+        guest.username = "carol".to_string();
+        let error = guest
+            .handle_command(Command::Create("lobby".to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind, ChatErrorKind::Permission);
+
+        server
+            .credentials
+            .lock()
+            .await
+            .register("alice", "hash".to_string())
+            .unwrap();
+        let mut owner = server.handler();
+        owner.username = "alice".to_string();
+        owner
+            .handle_command(Command::Create("lobby".to_string()))
+            .await
+            .unwrap();
+        let commands = [
+            Command::Op {
+                room: "lobby".to_string(),
+                user: "dave".to_string(),
+            },
+            Command::Invite {
+                room: "lobby".to_string(),
+                user: "dave".to_string(),
+            },
+        ];
+        for command in commands {
+            let error = owner.handle_command(command).await.unwrap_err();
+            assert_eq!(error.message, "dave is not a registered nickname");
+        }
+    }
+}
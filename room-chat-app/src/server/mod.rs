@@ -1,59 +1,249 @@
-use crate::common::ChatError;
-use tokio::net::TcpListener;
+use crate::common::{ChatError, ChatErrorKind};
+use tokio::net::{TcpListener, TcpStream};
+use std::future::poll_fn;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinSet;
 
+pub mod config;
 pub mod handler;
+pub mod room_actor;
 pub mod room_manager;
 pub mod client_manager;
+pub mod credentials;
+pub mod storage;
+
+pub use config::ServerConfig;
+
+/// How long connected clients get to wind down when the server shuts down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Pause after a failed accept, so running out of file descriptors does not
+/// turn into a busy loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Asks a running `ChatServer` to shut down. Cheap to clone.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+}
 
 pub struct ChatServer {
-    listener: TcpListener,
-    room_manager: Arc<Mutex<room_manager::RoomManager>>,
+    listeners: Vec<TcpListener>,
+    config: Arc<ServerConfig>,
+    room_manager: Arc<room_manager::RoomManager>,
     client_manager: Arc<Mutex<client_manager::ClientManager>>,
+    credentials: Arc<Mutex<credentials::CredentialStore>>,
+    logins: Arc<handler::PendingLogins>,
+    shutdown: ShutdownHandle,
 }
 
 impl ChatServer {
-    pub async fn new(addr: &str) -> Result<Self, ChatError> {
-        let listener = TcpListener::bind(addr).await?;
+    /// Validates `config`, binds every address in `config.listen` and
+    /// restores the rooms in its storage, creating any default rooms that are
+    /// missing.
+    pub async fn new(config: ServerConfig) -> Result<Self, ChatError> {
+        config.validate()?;
+        let mut listeners = Vec::new();
+        for addr in &config.listen {
+            let listener = TcpListener::bind(addr).await.map_err(|e| ChatError {
+                kind: ChatErrorKind::Config,
+                message: format!("Cannot listen on {}: {}", addr, e),
+            })?;
+            listeners.push(listener);
+        }
 
-        let room_manager = Arc::new(Mutex::new(room_manager::RoomManager::new()));
-        let client_manager = Arc::new(Mutex::new(client_manager::ClientManager::new()));
+        let client_manager = Arc::new(Mutex::new(
+            client_manager::ClientManager::new()
+                .with_max_clients(config.max_clients)
+                .with_queue_capacity(config.queue_capacity),
+        ));
+        let storage = storage::LogStorage::open(&config.storage_path)
+            .map_err(|e| ChatError {
+                kind: ChatErrorKind::Config,
+                message: format!("Cannot open {}: {}", config.storage_path.display(), e.message),
+            })?
+            .with_history_length(config.history_length);
+        let room_manager = room_manager::RoomManager::new(Arc::clone(&client_manager))
+            .with_history_length(config.history_length)
+            .with_history_replay(config.history_replay)
+            .load(Box::new(storage))?;
+        let credentials = Arc::new(Mutex::new(credentials::CredentialStore::open(
+            &config.credentials_path,
+            config.allow_guests,
+        )?));
 
-        // Create default lobby room
-        {
-            let mut rm = room_manager.lock().await;
-            rm.create_room("lobby".to_string()).await?;
+        // Create the default rooms unless they were restored
+        for room in &config.default_rooms {
+            if !room_manager.has_room(room).await {
+                room_manager.create_room(room.clone(), None).await?;
+            }
         }
+        let room_manager = Arc::new(room_manager);
 
         Ok(ChatServer {
-            listener,
+            listeners,
+            config: Arc::new(config),
             room_manager,
             client_manager,
+            credentials,
+            logins: Arc::new(handler::PendingLogins::default()),
+            shutdown: ShutdownHandle {
+                tx: Arc::new(watch::channel(false).0),
+            },
         })
     }
 
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// The addresses actually listened on, useful when binding port 0.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, ChatError> {
+        Ok(self
+            .listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Accepts the next connection on whichever listener has one first.
+    async fn accept(&self) -> Result<(TcpStream, SocketAddr), ChatError> {
+        let accepted = poll_fn(|cx| {
+            for listener in &self.listeners {
+                if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                    return Poll::Ready(accepted);
+                }
+            }
+            Poll::Pending
+        });
+        Ok(accepted.await?)
+    }
+
+    /// Accepts connections until shut down through a `ShutdownHandle`, Ctrl+C
+    /// or SIGTERM. Connected clients are then told and given `SHUTDOWN_GRACE`
+    /// to finish before history is flushed to disk.
     pub async fn run(self) -> Result<(), ChatError> {
+        for addr in self.local_addrs()? {
+            println!("Listening on {}", addr);
+        }
         println!("Server is running and ready to accept connections");
 
+        let mut shutdown = self.shutdown.tx.subscribe();
+        let signal = shutdown_signal();
+        tokio::pin!(signal);
+        let mut handlers = JoinSet::new();
         loop {
-            let (socket, addr) = self.listener.accept().await?;
-            println!("New connection from: {}", addr);
+            tokio::select! {
+                accepted = self.accept() => {
+                    // Failing to accept one connection is no reason to stop
+                    let (socket, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            eprintln!("Error accepting connection: {}", e);
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    println!("New connection from: {}", addr);
 
-            let rm = Arc::clone(&self.room_manager);
-            let cm = Arc::clone(&self.client_manager);
+                    let rm = Arc::clone(&self.room_manager);
+                    let cm = Arc::clone(&self.client_manager);
+                    let credentials = Arc::clone(&self.credentials);
+                    let config = Arc::clone(&self.config);
+                    let logins = Arc::clone(&self.logins);
+                    let shutdown = self.shutdown.tx.subscribe();
 
-            tokio::spawn(async move {
-                let username = format!("user_{}", addr.port()); // Temporary username based on port
-                match handler::ClientHandler::new(username, rm, cm).await {
-                    Ok(handler) => {
+                    handlers.spawn(async move {
+                        let handler = handler::ClientHandler::new(
+                            rm,
+                            cm,
+                            credentials,
+                            config,
+                            logins,
+                            shutdown,
+                        );
                         if let Err(e) = handler.handle(socket).await {
                             eprintln!("Error handling client {}: {}", addr, e);
                         }
-                    }
-                    Err(e) => eprintln!("Error creating handler for {}: {}", addr, e),
+                    });
                 }
-            });
+                // Reap finished handlers as we go
+                Some(_) = handlers.join_next() => {}
+                _ = shutdown.wait_for(|&stop| stop) => break,
+                _ = &mut signal => {
+                    self.shutdown.shutdown();
+                    break;
+                }
+            }
         }
+
+        println!("Shutting down, waiting for {} connections", handlers.len());
+        drop(self.listeners);
+        let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            while handlers.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            eprintln!("Dropping {} connections that did not finish", handlers.len());
+            handlers.shutdown().await;
+        }
+
+        self.room_manager.flush().await?;
+        println!("Server stopped");
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Resolves on Ctrl+C, or on SIGTERM where there is such a thing. Signals
+/// that cannot be listened for never arrive.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_an_invalid_config() {
+        let config = ServerConfig {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            max_message_size: 1,
+            ..ServerConfig::default()
+        };
+        let error = ChatServer::new(config).await.err().unwrap();
+        assert_eq!(error.kind, ChatErrorKind::Config);
+        assert!(error.message.contains("max_message_size"));
+    }
+}
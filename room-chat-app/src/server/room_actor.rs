@@ -0,0 +1,1075 @@
+use super::client_manager::{ClientManager, SessionSender};
+use super::credentials;
+use super::storage::StorageWriter;
+use crate::common::protocol::{nickname_key, MAX_BAN_DURATION};
+use crate::common::{
+    ChatError, ChatErrorKind, Message, ModeChange, Role, Room, RoomEvent, ServerFrame,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+// Requests a room queues up before callers have to wait
+const REQUEST_CAPACITY: usize = 256;
+
+
+type Responder<T> = oneshot::Sender<Result<T, ChatError>>;
+
+fn check_owner(room: &Room, username: &str, action: &str) -> Result<(), ChatError> {
+    if room.role(username) == Role::Owner {
+        Ok(())
+    } else {
+        Err(ChatError {
+            kind: ChatErrorKind::Permission,
+            message: format!("Only the room owner can {} it", action),
+        })
+    }
+}
+
+fn check_moderator(room: &Room, username: &str) -> Result<(), ChatError> {
+    require_moderator(room.role(username))
+}
+
+fn require_moderator(role: Role) -> Result<(), ChatError> {
+    if role >= Role::Moderator {
+        Ok(())
+    } else {
+        Err(ChatError {
+            kind: ChatErrorKind::Permission,
+            message: "You are not a moderator of this room".to_string(),
+        })
+    }
+}
+
+/// Moderators may act on members, owners on everyone else.
+fn check_can_moderate(room: &Room, username: &str, target: &str) -> Result<(), ChatError> {
+    check_moderator(room, username)?;
+    let message = if room.role(target) >= room.role(username) {
+        format!("You cannot moderate {}", target)
+    } else {
+        return Ok(());
+    };
+    Err(ChatError {
+        kind: ChatErrorKind::Permission,
+        message,
+    })
+}
+
+fn room_gone() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Room,
+        message: "Room does not exist".to_string(),
+    }
+}
+
+fn password_needed() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Permission,
+        message: "This room needs a password: /join <room> <password>".to_string(),
+    }
+}
+
+fn wrong_password() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Permission,
+        message: "Wrong password".to_string(),
+    }
+}
+
+fn system_notice(room_name: &str, content: String) -> ServerFrame {
+    ServerFrame::System(Message::new(
+        room_name.to_string(),
+        "System".to_string(),
+        content,
+    ))
+}
+
+enum Request {
+    Join {
+        username: String,
+        password: Option<String>,
+        replay: usize,
+        reply: Responder<Vec<Message>>,
+    },
+    // A join whose password checked out while the room carried on
+    Admit {
+        username: String,
+        replay: usize,
+        reply: Responder<Vec<Message>>,
+    },
+    Leave {
+        username: String,
+        content: String,
+        reply: Responder<()>,
+    },
+    Chat {
+        message: Message,
+        reply: Responder<()>,
+    },
+    SetRole {
+        username: String,
+        target: String,
+        role: Role,
+        reply: Responder<()>,
+    },
+    Kick {
+        username: String,
+        target: String,
+        reason: Option<String>,
+        reply: Responder<()>,
+    },
+    Ban {
+        username: String,
+        target: String,
+        duration: Option<Duration>,
+        reply: Responder<()>,
+    },
+    Unban {
+        username: String,
+        target: String,
+        reply: Responder<()>,
+    },
+    Invite {
+        username: String,
+        target: String,
+        reply: Responder<()>,
+    },
+    Role {
+        username: String,
+        reply: Responder<Role>,
+    },
+    SetMode {
+        username: String,
+        change: ModeChange,
+        // Hashed by the caller, a new password is the slow part
+        password_hash: Option<String>,
+        reply: Responder<()>,
+    },
+    Rename {
+        username: String,
+        new_name: String,
+        reply: Responder<()>,
+    },
+    Delete {
+        username: String,
+        reply: Responder<()>,
+    },
+    History {
+        username: String,
+        limit: usize,
+        before: Option<u64>,
+        reply: Responder<Vec<Message>>,
+    },
+    Users {
+        username: String,
+        reply: Responder<Vec<String>>,
+    },
+    VisibleTo {
+        username: String,
+        reply: Responder<bool>,
+    },
+}
+
+impl Request {
+    /// Who is asking, `None` for a question about someone.
+    fn username(&self) -> Option<&str> {
+        match self {
+            Request::Join { username, .. }
+            | Request::Admit { username, .. }
+            | Request::Leave { username, .. }
+            | Request::SetRole { username, .. }
+            | Request::Kick { username, .. }
+            | Request::Ban { username, .. }
+            | Request::Unban { username, .. }
+            | Request::Invite { username, .. }
+            | Request::Role { username, .. }
+            | Request::SetMode { username, .. }
+            | Request::Rename { username, .. }
+            | Request::Delete { username, .. }
+            | Request::History { username, .. }
+            | Request::Users { username, .. } => Some(username),
+            Request::Chat { message, .. } => Some(&message.sender),
+            Request::VisibleTo { .. } => None,
+        }
+    }
+
+    /// Answers the request with `error` instead of carrying it out.
+    fn refuse(self, error: ChatError) {
+        match self {
+            Request::Join { reply, .. }
+            | Request::Admit { reply, .. }
+            | Request::History { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::Users { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::Role { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::VisibleTo { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Request::Leave { reply, .. }
+            | Request::Chat { reply, .. }
+            | Request::SetRole { reply, .. }
+            | Request::Kick { reply, .. }
+            | Request::Ban { reply, .. }
+            | Request::Unban { reply, .. }
+            | Request::Invite { reply, .. }
+            | Request::SetMode { reply, .. }
+            | Request::Rename { reply, .. }
+            | Request::Delete { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+        }
+    }
+}
+
+/// A running room. Each room is a task owning its `Room` and working through
+/// requests in order, so rooms make progress independently of each other.
+/// Handles are cheap to clone; requests to a deleted room fail with a room
+/// error.
+#[derive(Clone)]
+pub struct RoomHandle {
+    tx: mpsc::Sender<Request>,
+}
+
+impl RoomHandle {
+    /// Starts the task for `room`, delivering to the sessions in `clients`
+    /// and keeping up to `history_length` messages.
+    pub fn spawn(
+        room: Room,
+        clients: Arc<Mutex<ClientManager>>,
+        storage: StorageWriter,
+        history_length: usize,
+    ) -> Self {
+        let (tx, requests) = mpsc::channel(REQUEST_CAPACITY);
+        let actor = RoomActor {
+            room,
+            history_length,
+            members: HashMap::new(),
+            clients,
+            storage,
+            requests,
+            handle: tx.downgrade(),
+        };
+        let task = tokio::spawn(actor.run());
+        tokio::spawn(async move {
+            if let Err(e) = task.await {
+                eprintln!("Room task stopped: {}", e);
+            }
+        });
+        RoomHandle { tx }
+    }
+
+    /// Whether the room's task is still taking requests. It stops once the
+    /// room is deleted, or if it panics.
+    pub fn is_running(&self) -> bool {
+        !self.tx.is_closed()
+    }
+
+    async fn call<T>(&self, request: impl FnOnce(Responder<T>) -> Request) -> Result<T, ChatError> {
+        let (reply, response) = oneshot::channel();
+        self.tx
+            .send(request(reply))
+            .await
+            .map_err(|_| room_gone())?;
+        response.await.map_err(|_| room_gone())?
+    }
+
+    /// Adds the user to the room and returns up to `replay` recent messages.
+    /// Invited users, moderators and the owner skip the room's mode checks.
+    pub async fn join(
+        &self,
+        username: &str,
+        password: Option<String>,
+        replay: usize,
+    ) -> Result<Vec<Message>, ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::Join {
+            username,
+            password,
+            replay,
+            reply,
+        })
+        .await
+    }
+
+    /// Takes the user out of the room, telling the others `content`. Does
+    /// nothing if they are not in it.
+    pub async fn leave(&self, username: &str, content: String) -> Result<(), ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::Leave {
+            username,
+            content,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn chat(&self, message: Message) -> Result<(), ChatError> {
+        self.call(|reply| Request::Chat { message, reply }).await
+    }
+
+    pub async fn set_role(
+        &self,
+        username: &str,
+        target: &str,
+        role: Role,
+    ) -> Result<(), ChatError> {
+        let (username, target) = (username.to_string(), target.to_string());
+        self.call(|reply| Request::SetRole {
+            username,
+            target,
+            role,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn kick(
+        &self,
+        username: &str,
+        target: &str,
+        reason: Option<String>,
+    ) -> Result<(), ChatError> {
+        let (username, target) = (username.to_string(), target.to_string());
+        self.call(|reply| Request::Kick {
+            username,
+            target,
+            reason,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn ban(
+        &self,
+        username: &str,
+        target: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), ChatError> {
+        let (username, target) = (username.to_string(), target.to_string());
+        self.call(|reply| Request::Ban {
+            username,
+            target,
+            duration,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn unban(&self, username: &str, target: &str) -> Result<(), ChatError> {
+        let (username, target) = (username.to_string(), target.to_string());
+        self.call(|reply| Request::Unban {
+            username,
+            target,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn invite(&self, username: &str, target: &str) -> Result<(), ChatError> {
+        let (username, target) = (username.to_string(), target.to_string());
+        self.call(|reply| Request::Invite {
+            username,
+            target,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn role(&self, username: &str) -> Result<Role, ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::Role { username, reply }).await
+    }
+
+    pub async fn set_mode(&self, username: &str, change: ModeChange) -> Result<(), ChatError> {
+        // Only hash for moderators, the room checks again when it applies it
+        let password_hash = match &change {
+            ModeChange::Password(Some(password)) => {
+                require_moderator(self.role(username).await?)?;
+                Some(credentials::hash(password.clone()).await?)
+            }
+            _ => None,
+        };
+
+        let username = username.to_string();
+        self.call(|reply| Request::SetMode {
+            username,
+            change,
+            password_hash,
+            reply,
+        })
+        .await
+    }
+
+    /// Renames the room. The caller makes sure `new_name` is valid and free.
+    pub async fn rename(&self, username: &str, new_name: &str) -> Result<(), ChatError> {
+        let (username, new_name) = (username.to_string(), new_name.to_string());
+        self.call(|reply| Request::Rename {
+            username,
+            new_name,
+            reply,
+        })
+        .await
+    }
+
+    /// Deletes the room and stops its task.
+    pub async fn delete(&self, username: &str) -> Result<(), ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::Delete { username, reply }).await
+    }
+
+    /// A page of the room's history, see [`Room::history_page`]. Only members
+    /// of the room may read it.
+    pub async fn history(
+        &self,
+        username: &str,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<Vec<Message>, ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::History {
+            username,
+            limit,
+            before,
+            reply,
+        })
+        .await
+    }
+
+    /// The room's members, if the room is visible to `username`.
+    pub async fn users(&self, username: &str) -> Result<Vec<String>, ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::Users { username, reply }).await
+    }
+
+    /// Whether the room shows up in `username`'s room list.
+    pub async fn visible_to(&self, username: &str) -> Result<bool, ChatError> {
+        let username = username.to_string();
+        self.call(|reply| Request::VisibleTo { username, reply })
+            .await
+    }
+}
+
+struct RoomActor {
+    room: Room,
+    history_length: usize,
+    // Where to deliver to the members who are online
+    members: HashMap<String, SessionSender>,
+    clients: Arc<Mutex<ClientManager>>,
+    storage: StorageWriter,
+    requests: mpsc::Receiver<Request>,
+    // Weak, so the task ends once every handle is gone
+    handle: mpsc::WeakSender<Request>,
+}
+
+impl RoomActor {
+    async fn run(mut self) {
+        while let Some(request) = self.requests.recv().await {
+            // A hidden room is not there for those it is hidden from, whatever
+            // they ask of it
+            if request.username().is_some_and(|u| !self.visible_to(u)) {
+                request.refuse(room_gone());
+                continue;
+            }
+            // Replies to callers who gave up waiting are dropped
+            match request {
+                Request::Join {
+                    username,
+                    password,
+                    replay,
+                    reply,
+                } => self.join(username, password, replay, reply).await,
+                Request::Admit {
+                    username,
+                    replay,
+                    reply,
+                } => {
+                    let _ = reply.send(self.admit(&username, replay).await);
+                }
+                Request::Leave {
+                    username,
+                    content,
+                    reply,
+                } => {
+                    let _ = reply.send(self.depart(&username, content).await);
+                }
+                Request::Chat { message, reply } => {
+                    let _ = reply.send(self.chat(message).await);
+                }
+                Request::SetRole {
+                    username,
+                    target,
+                    role,
+                    reply,
+                } => {
+                    let _ = reply.send(self.set_role(&username, &target, role).await);
+                }
+                Request::Kick {
+                    username,
+                    target,
+                    reason,
+                    reply,
+                } => {
+                    let _ = reply.send(self.kick(&username, &target, reason).await);
+                }
+                Request::Ban {
+                    username,
+                    target,
+                    duration,
+                    reply,
+                } => {
+                    let _ = reply.send(self.ban(&username, &target, duration).await);
+                }
+                Request::Unban {
+                    username,
+                    target,
+                    reply,
+                } => {
+                    let _ = reply.send(self.unban(&username, &target).await);
+                }
+                Request::Invite {
+                    username,
+                    target,
+                    reply,
+                } => {
+                    let _ = reply.send(self.invite(&username, &target).await);
+                }
+                Request::Role { username, reply } => {
+                    let _ = reply.send(Ok(self.room.role(&username)));
+                }
+                Request::SetMode {
+                    username,
+                    change,
+                    password_hash,
+                    reply,
+                } => {
+                    let _ = reply.send(self.set_mode(&username, change, password_hash).await);
+                }
+                Request::Rename {
+                    username,
+                    new_name,
+                    reply,
+                } => {
+                    let _ = reply.send(self.rename(&username, new_name).await);
+                }
+                Request::Delete { username, reply } => {
+                    let result = self.delete(&username).await;
+                    let deleted = result.is_ok();
+                    let _ = reply.send(result);
+                    if deleted {
+                        break;
+                    }
+                }
+                Request::History {
+                    username,
+                    limit,
+                    before,
+                    reply,
+                } => {
+                    let _ = reply.send(self.history(&username, limit, before));
+                }
+                Request::Users { reply, .. } => {
+                    let _ = reply.send(Ok(self.room.users.clone()));
+                }
+                Request::VisibleTo { username, reply } => {
+                    let _ = reply.send(Ok(self.visible_to(&username)));
+                }
+            }
+        }
+    }
+
+    /// A hidden room is only there for its members, those invited to it, its
+    /// moderators and its owner.
+    fn visible_to(&self, username: &str) -> bool {
+        let room = &self.room;
+        !room.mode.hidden
+            || room.is_member(username)
+            || room.is_invited(username)
+            || room.role(username) >= Role::Moderator
+    }
+
+    /// Checks whether `username` may join. Returns the password hash they
+    /// still have to match, if any.
+    fn admission(&mut self, username: &str) -> Result<Option<String>, ChatError> {
+        let room = &mut self.room;
+        if room.is_banned(username) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Permission,
+                message: "You are banned from this room".to_string(),
+            });
+        }
+
+        let admitted = room.is_member(username)
+            || room.is_invited(username)
+            || room.role(username) >= Role::Moderator;
+        if admitted {
+            return Ok(None);
+        }
+        if room.mode.invite_only {
+            return Err(ChatError {
+                kind: ChatErrorKind::Permission,
+                message: "This room is invite-only".to_string(),
+            });
+        }
+        Ok(room.mode.password_hash.clone())
+    }
+
+    async fn join(
+        &mut self,
+        username: String,
+        password: Option<String>,
+        replay: usize,
+        reply: Responder<Vec<Message>>,
+    ) {
+        let hash = match self.admission(&username) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => {
+                let _ = reply.send(self.admit(&username, replay).await);
+                return;
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                return;
+            }
+        };
+        let Some(password) = password else {
+            let _ = reply.send(Err(password_needed()));
+            return;
+        };
+
+        // Verifying is slow, the room carries on meanwhile and admits the
+        // user once it is done
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            let valid = credentials::verify(password, hash).await;
+            match valid {
+                Ok(true) => {}
+                Ok(false) => {
+                    let _ = reply.send(Err(wrong_password()));
+                    return;
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                    return;
+                }
+            }
+            if let Some(tx) = handle.upgrade() {
+                let _ = tx
+                    .send(Request::Admit {
+                        username,
+                        replay,
+                        reply,
+                    })
+                    .await;
+            }
+        });
+    }
+
+    async fn admit(&mut self, username: &str, replay: usize) -> Result<Vec<Message>, ChatError> {
+        // Banned while the password was being checked
+        if self.room.is_banned(username) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Permission,
+                message: "You are banned from this room".to_string(),
+            });
+        }
+
+        let room = &mut self.room;
+        let history = room.history_page(replay, None).unwrap_or_default();
+        let added = room.add_user(username.to_string());
+        // Membership is not stored, but a used up invitation is
+        if room.take_invitation(username) {
+            self.storage.save_room(&self.room).await?;
+        }
+        let sender = self.clients.lock().await.joined(username, &self.room.name);
+        if let Some(sender) = sender {
+            self.members.insert(nickname_key(username), sender);
+        }
+        if added {
+            self.member_event(RoomEvent::MemberJoined {
+                room: self.room.name.clone(),
+                user: username.to_string(),
+            });
+            let content = format!("{} has joined the room", username);
+            self.broadcast(system_notice(&self.room.name, content))
+                .await?;
+        }
+        Ok(history)
+    }
+
+    async fn depart(&mut self, username: &str, content: String) -> Result<(), ChatError> {
+        if self.room.remove_user(username) {
+            self.remove_member(username).await;
+            self.member_left(username);
+            self.broadcast(system_notice(&self.room.name, content))
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Follows up on `username` having been taken out of `room.users`
+    async fn remove_member(&mut self, username: &str) {
+        self.members.remove(&nickname_key(username));
+        self.clients.lock().await.left(username, &self.room.name);
+    }
+
+    async fn chat(&mut self, mut message: Message) -> Result<(), ChatError> {
+        let room = &mut self.room;
+        if !room.is_member(&message.sender) || room.is_banned(&message.sender) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Permission,
+                message: "You are not in this room".to_string(),
+            });
+        }
+
+        // The sender may still know the room by the name it had before a rename
+        message.room = room.name.clone();
+        self.broadcast(ServerFrame::Chat(message)).await
+    }
+
+    /// Makes `target` a moderator or a plain member. Only the owner may.
+    async fn set_role(
+        &mut self,
+        username: &str,
+        target: &str,
+        role: Role,
+    ) -> Result<(), ChatError> {
+        let room = &mut self.room;
+        check_owner(room, username, "change roles in")?;
+        if room.role(target) == Role::Owner {
+            return Err(ChatError {
+                kind: ChatErrorKind::Permission,
+                message: "The owner's role cannot be changed".to_string(),
+            });
+        }
+
+        room.set_moderator(target, role == Role::Moderator);
+        let content = if role == Role::Moderator {
+            format!("{} is now a moderator", target)
+        } else {
+            format!("{} is no longer a moderator", target)
+        };
+        self.storage.save_room(&self.room).await?;
+        self.broadcast(system_notice(&self.room.name, content))
+            .await
+    }
+
+    async fn kick(
+        &mut self,
+        username: &str,
+        target: &str,
+        reason: Option<String>,
+    ) -> Result<(), ChatError> {
+        let room = &self.room;
+        check_can_moderate(room, username, target)?;
+        if !room.is_member(target) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Room,
+                message: format!("{} is not in this room", target),
+            });
+        }
+
+        let mut content = format!("{} was kicked by {}", target, username);
+        if let Some(reason) = reason {
+            content = format!("{} ({})", content, reason);
+        }
+        // Announce before removing so the kicked user hears about it too
+        self.member_left(target);
+        self.broadcast(system_notice(&self.room.name, content))
+            .await?;
+        self.room.remove_user(target);
+        self.remove_member(target).await;
+        Ok(())
+    }
+
+    /// Bans `target` from the room, for `duration` or until unbanned, and
+    /// removes them if they are in it.
+    async fn ban(
+        &mut self,
+        username: &str,
+        target: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), ChatError> {
+        let room = &mut self.room;
+        check_can_moderate(room, username, target)?;
+
+        let until = match duration {
+            Some(d) => {
+                let until = SystemTime::now()
+                    .checked_add(d)
+                    .filter(|_| d.as_secs() <= MAX_BAN_DURATION);
+                Some(until.ok_or_else(|| ChatError {
+                    kind: ChatErrorKind::Command,
+                    message: format!(
+                        "Ban duration must be at most {} days",
+                        MAX_BAN_DURATION / (24 * 60 * 60)
+                    ),
+                })?)
+            }
+            None => None,
+        };
+        room.ban(target, until);
+        self.storage.save_room(&self.room).await?;
+        let content = match duration {
+            Some(d) => format!("{} was banned by {} for {}s", target, username, d.as_secs()),
+            None => format!("{} was banned by {}", target, username),
+        };
+        if self.room.is_member(target) {
+            self.member_left(target);
+        }
+        self.broadcast(system_notice(&self.room.name, content))
+            .await?;
+        self.room.remove_user(target);
+        self.remove_member(target).await;
+        Ok(())
+    }
+
+    async fn unban(&mut self, username: &str, target: &str) -> Result<(), ChatError> {
+        let room = &mut self.room;
+        check_moderator(room, username)?;
+        if !room.unban(target) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Room,
+                message: format!("{} is not banned", target),
+            });
+        }
+        self.storage.save_room(&self.room).await?;
+        let content = format!("{} was unbanned by {}", target, username);
+        self.broadcast(system_notice(&self.room.name, content))
+            .await
+    }
+
+    /// Lets `target` into the room whatever its mode, and tells them so.
+    async fn invite(&mut self, username: &str, target: &str) -> Result<(), ChatError> {
+        let room = &mut self.room;
+        check_moderator(room, username)?;
+        room.invite(target);
+        self.storage.save_room(&self.room).await?;
+
+        let content = format!("{} invited you to {}", username, self.room.name);
+        let notice = system_notice(&self.room.name, content);
+        // Not a member, so only the registry knows where they are
+        self.clients.lock().await.send(target, notice);
+        Ok(())
+    }
+
+    async fn set_mode(
+        &mut self,
+        username: &str,
+        change: ModeChange,
+        password_hash: Option<String>,
+    ) -> Result<(), ChatError> {
+        let room = &mut self.room;
+        check_moderator(room, username)?;
+
+        let content = match change {
+            ModeChange::InviteOnly(on) => {
+                room.mode.invite_only = on;
+                format!(
+                    "{} made the room {}",
+                    username,
+                    if on { "invite-only" } else { "open" }
+                )
+            }
+            ModeChange::Hidden(on) => {
+                room.mode.hidden = on;
+                let state = if on { "hidden from" } else { "visible in" };
+                format!("{} made the room {} the room list", username, state)
+            }
+            ModeChange::Password(_) => {
+                let state = if password_hash.is_some() {
+                    "set"
+                } else {
+                    "removed"
+                };
+                room.mode.password_hash = password_hash;
+                format!("{} {} the room password", username, state)
+            }
+        };
+        self.storage.save_room(&self.room).await?;
+        self.broadcast(system_notice(&self.room.name, content))
+            .await
+    }
+
+    async fn rename(&mut self, username: &str, new_name: String) -> Result<(), ChatError> {
+        check_owner(&self.room, username, "rename")?;
+
+        let old_name = std::mem::replace(&mut self.room.name, new_name.clone());
+        for message in self.room.history.iter_mut() {
+            message.room = new_name.clone();
+        }
+        self.storage.rename_room(&old_name, &new_name).await?;
+        self.clients.lock().await.room_renamed(&old_name, &new_name);
+        self.member_event(RoomEvent::Renamed {
+            room: old_name.clone(),
+            new_name: new_name.clone(),
+        });
+
+        let content = format!(
+            "Room {} was renamed to {} by {}",
+            old_name, new_name, username
+        );
+        self.broadcast(system_notice(&new_name, content)).await
+    }
+
+    async fn delete(&mut self, username: &str) -> Result<(), ChatError> {
+        check_owner(&self.room, username, "delete")?;
+
+        let room_name = &self.room.name;
+        self.storage.delete_room(room_name).await?;
+        {
+            let mut clients = self.clients.lock().await;
+            for user in &self.room.users {
+                clients.left(user, room_name);
+            }
+        }
+
+        self.member_event(RoomEvent::Deleted(room_name.clone()));
+        let content = format!("Room {} was deleted by {}", room_name, username);
+        self.send_to_members(system_notice(room_name, content));
+        Ok(())
+    }
+
+    fn history(
+        &self,
+        username: &str,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Result<Vec<Message>, ChatError> {
+        if !self.room.is_member(username) {
+            return Err(ChatError {
+                kind: ChatErrorKind::Room,
+                message: "You are not in this room".to_string(),
+            });
+        }
+
+        self.room.history_page(limit, before).ok_or(ChatError {
+            kind: ChatErrorKind::Room,
+            message: "Message is no longer in the room history".to_string(),
+        })
+    }
+
+    /// Sends `frame` to every member of the room, recording chat and system
+    /// messages in the room's history.
+    /// Sends `frame` to the members, keeping it in the history if it is a
+    /// chat message. Notices only matter to whoever is there to see them.
+    async fn broadcast(&mut self, frame: ServerFrame) -> Result<(), ChatError> {
+        if let ServerFrame::Chat(message) = &frame {
+            self.storage.append_message(message).await?;
+            self.room.add_message(message.clone(), self.history_length);
+        }
+        self.send_to_members(frame);
+        Ok(())
+    }
+
+    fn member_event(&self, event: RoomEvent) {
+        self.send_to_members(ServerFrame::Event(event));
+    }
+
+    fn member_left(&self, username: &str) {
+        self.member_event(RoomEvent::MemberLeft {
+            room: self.room.name.clone(),
+            user: username.to_string(),
+        });
+    }
+
+    fn send_to_members(&self, frame: ServerFrame) {
+        // Members who are offline simply miss it
+        for sender in self.members.values() {
+            sender.send(frame.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::MemoryStorage;
+
+    fn hidden_room() -> RoomHandle {
+        let mut room = Room::new("secret".to_string());
+        room.owner = Some("alice".to_string());
+        room.moderators.push("mod".to_string());
+        room.invited.push("guest".to_string());
+        room.mode.hidden = true;
+        room.mode.password_hash = Some("not a hash".to_string());
+        RoomHandle::spawn(
+            room,
+            Arc::new(Mutex::new(ClientManager::new())),
+            StorageWriter::spawn(Box::new(MemoryStorage)),
+            10,
+        )
+    }
+
+    #[tokio::test]
+    async fn shows_a_hidden_room_to_its_owner_moderators_and_invitees() {
+        let room = hidden_room();
+        for username in ["alice", "mod", "guest"] {
+            assert!(room.visible_to(username).await.unwrap());
+        }
+        assert!(!room.visible_to("eve").await.unwrap());
+        assert!(room.users("alice").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn denies_a_hidden_room_exists_to_everyone_else() {
+        let room = hidden_room();
+        let errors = [
+            room.join("eve", None, 10).await.unwrap_err(),
+            room.join("eve", Some("guess".to_string()), 10).await.unwrap_err(),
+            room.history("eve", 10, None).await.unwrap_err(),
+            room.users("eve").await.unwrap_err(),
+            room.invite("eve", "bob").await.unwrap_err(),
+            room.delete("eve").await.unwrap_err(),
+        ];
+        for error in errors {
+            assert_eq!(error.message, room_gone().message);
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_nicknames_regardless_of_case() {
+        let mut room = Room::new("lobby".to_string());
+        room.owner = Some("alice".to_string());
+        let room = RoomHandle::spawn(
+            room,
+            Arc::new(Mutex::new(ClientManager::new())),
+            StorageWriter::spawn(Box::new(MemoryStorage)),
+            10,
+        );
+        room.set_role("ALICE", "Bob", Role::Moderator).await.unwrap();
+        assert_eq!(room.role("bob").await.unwrap(), Role::Moderator);
+
+        room.join("carol", None, 10).await.unwrap();
+        room.ban("bob", "Carol", None).await.unwrap();
+        assert!(room.users("alice").await.unwrap().is_empty());
+        assert!(room.join("CAROL", None, 10).await.is_err());
+
+        room.unban("alice", "carol").await.unwrap();
+        room.join("CAROL", None, 10).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_only_chat_in_the_history() {
+        let room = RoomHandle::spawn(
+            Room::new("lobby".to_string()),
+            Arc::new(Mutex::new(ClientManager::new())),
+            StorageWriter::spawn(Box::new(MemoryStorage)),
+            10,
+        );
+        room.join("alice", None, 10).await.unwrap();
+        room.join("bob", None, 10).await.unwrap();
+        let hello = Message::new("lobby".to_string(), "alice".to_string(), "hi".to_string());
+        room.chat(hello).await.unwrap();
+        room.leave("bob", "bob left".to_string()).await.unwrap();
+
+        let history = room.history("alice", 10, None).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "hi");
+    }
+}
@@ -1,91 +1,460 @@
-use crate::common::{ChatError, Message, Room};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use super::client_manager::ClientManager;
+use super::room_actor::RoomHandle;
+use super::storage::{MemoryStorage, Storage, StorageWriter};
+use crate::common::protocol::{room_key, validate_room_name};
+use crate::common::room::DEFAULT_MAX_HISTORY;
+use crate::common::{ChatError, ChatErrorKind, Message, ModeChange, Role, Room};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
 
+pub const DEFAULT_HISTORY_REPLAY: usize = 20;
+
+fn no_such_room() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Room,
+        message: "Room does not exist".to_string(),
+    }
+}
+
+fn room_exists() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Room,
+        message: "Room already exists".to_string(),
+    }
+}
+
+/// Drops rooms whose task has stopped, which only happens when it panicked,
+/// so their names can be used again.
+fn forget_stopped(rooms: &mut Directory) {
+    rooms.retain(|_, (_, room)| room.is_running());
+}
+
+// Rooms by `room_key`, each with its name as it is spelled
+type Directory = HashMap<String, (String, RoomHandle)>;
+
+/// The directory of rooms. Each room runs as its own task (see
+/// [`RoomHandle`]); the directory is only locked to look a room up, and
+/// briefly for writing when rooms are created, renamed or deleted.
 pub struct RoomManager {
-    rooms: HashMap<String, Room>,
-    clients: HashMap<String, mpsc::Sender<Message>>,
+    rooms: RwLock<Directory>,
+    // Keys of rooms being renamed or deleted, and of the names they are
+    // being renamed to. Only used under the directory's write lock.
+    changing: std::sync::Mutex<HashSet<String>>,
+    clients: Arc<Mutex<ClientManager>>,
+    history_length: usize,
+    history_replay: usize,
+    storage: StorageWriter,
 }
 
 impl RoomManager {
-    pub fn new() -> Self {
+    /// Creates a room manager delivering to the sessions in `clients`.
+    pub fn new(clients: Arc<Mutex<ClientManager>>) -> Self {
         RoomManager {
-            rooms: HashMap::new(),
-            clients: HashMap::new(),
+            rooms: RwLock::new(HashMap::new()),
+            changing: std::sync::Mutex::new(HashSet::new()),
+            clients,
+            history_length: DEFAULT_MAX_HISTORY,
+            history_replay: DEFAULT_HISTORY_REPLAY,
+            storage: StorageWriter::spawn(Box::new(MemoryStorage)),
+        }
+    }
+
+    /// Restores the rooms recorded in `storage` and keeps recording into it,
+    /// in place of any rooms created so far.
+    pub fn load(mut self, mut storage: Box<dyn Storage>) -> Result<Self, ChatError> {
+        let rooms = storage.load_rooms()?;
+        self.storage = StorageWriter::spawn(storage);
+        let rooms = rooms
+            .into_iter()
+            .map(|room| {
+                let key = room_key(&room.name);
+                (key, (room.name.clone(), self.spawn_room(room)))
+            })
+            .collect();
+        self.rooms = RwLock::new(rooms);
+        Ok(self)
+    }
+
+    fn spawn_room(&self, room: Room) -> RoomHandle {
+        RoomHandle::spawn(
+            room,
+            Arc::clone(&self.clients),
+            self.storage.clone(),
+            self.history_length,
+        )
+    }
+
+    pub async fn has_room(&self, name: &str) -> bool {
+        self.rooms.read().await.contains_key(&room_key(name))
+    }
+
+    pub async fn flush(&self) -> Result<(), ChatError> {
+        self.storage.flush().await
+    }
+
+    /// Sets how many messages each room keeps. Applies to rooms created or
+    /// loaded afterwards.
+    pub fn with_history_length(mut self, history_length: usize) -> Self {
+        self.history_length = history_length;
+        self
+    }
+
+    /// Sets how many recent messages a user receives after joining a room.
+    pub fn with_history_replay(mut self, history_replay: usize) -> Self {
+        self.history_replay = history_replay;
+        self
+    }
+
+    /// Looks up a running room, whatever the case `name` is given in.
+    pub async fn room(&self, name: &str) -> Result<RoomHandle, ChatError> {
+        let handle = self
+            .rooms
+            .read()
+            .await
+            .get(&room_key(name))
+            .map(|(_, room)| room.clone());
+        match handle {
+            Some(handle) if handle.is_running() => Ok(handle),
+            Some(_) => {
+                forget_stopped(&mut *self.rooms.write().await);
+                Err(no_such_room())
+            }
+            None => Err(no_such_room()),
+        }
+    }
+
+    /// The name of the room `name` refers to, spelled the way the room is.
+    pub async fn room_name(&self, name: &str) -> Option<String> {
+        let rooms = self.rooms.read().await;
+        rooms.get(&room_key(name)).map(|(name, _)| name.clone())
+    }
+
+    /// Creates a room owned by `owner`, or by the server when `None`.
+    pub async fn create_room(&self, name: String, owner: Option<String>) -> Result<(), ChatError> {
+        validate_room_name(&name)?;
+        let mut rooms = self.rooms.write().await;
+        forget_stopped(&mut rooms);
+        let key = room_key(&name);
+        if rooms.contains_key(&key) || self.changing.lock().unwrap().contains(&key) {
+            return Err(room_exists());
         }
+        let mut room = Room::new(name.clone());
+        room.owner = owner;
+        self.storage.save_room(&room).await?;
+        rooms.insert(key, (name, self.spawn_room(room)));
+        Ok(())
     }
 
-    pub async fn create_room(&mut self, name: String) -> Result<(), ChatError> {
-        if self.rooms.contains_key(&name) {
+    /// Marks the room `name` as changing, along with `new_name` if it is
+    /// being renamed, and returns it. Neither name can be taken, nor the room
+    /// renamed or deleted again, until `finish_change`. A room may be renamed
+    /// to its own name spelled differently.
+    async fn start_change(
+        &self,
+        name: &str,
+        new_name: Option<&str>,
+    ) -> Result<RoomHandle, ChatError> {
+        let (key, new_key) = (room_key(name), new_name.map(room_key));
+        let mut rooms = self.rooms.write().await;
+        forget_stopped(&mut rooms);
+        let mut changing = self.changing.lock().unwrap();
+        if let Some(new_key) = new_key.as_ref().filter(|&new_key| *new_key != key) {
+            if rooms.contains_key(new_key) || changing.contains(new_key) {
+                return Err(room_exists());
+            }
+        }
+        let (_, handle) = rooms.get(&key).cloned().ok_or_else(no_such_room)?;
+        if changing.contains(&key) {
             return Err(ChatError {
-                kind: crate::common::ChatErrorKind::Room,
-                message: "Room already exists".to_string(),
+                kind: ChatErrorKind::Room,
+                message: "Room is already being renamed or deleted".to_string(),
             });
         }
-        self.rooms.insert(name.clone(), Room::new(name));
+        changing.extend(std::iter::once(key).chain(new_key));
+        Ok(handle)
+    }
+
+    /// Ends a change begun with `start_change`, handing back the directory to
+    /// update with its outcome.
+    async fn finish_change(
+        &self,
+        name: &str,
+        new_name: Option<&str>,
+    ) -> tokio::sync::RwLockWriteGuard<'_, Directory> {
+        let rooms = self.rooms.write().await;
+        let mut changing = self.changing.lock().unwrap();
+        for name in std::iter::once(name).chain(new_name) {
+            changing.remove(&room_key(name));
+        }
+        rooms
+    }
+
+    pub async fn rename_room(
+        &self,
+        username: &str,
+        old_name: &str,
+        new_name: String,
+    ) -> Result<(), ChatError> {
+        validate_room_name(&new_name)?;
+        let handle = self.start_change(old_name, Some(&new_name)).await?;
+
+        // The room may have a backlog to get through first, the directory is
+        // not locked meanwhile so other rooms carry on
+        let renamed = handle.rename(username, &new_name).await;
+        let mut rooms = self.finish_change(old_name, Some(&new_name)).await;
+        renamed?;
+        rooms.remove(&room_key(old_name));
+        rooms.insert(room_key(&new_name), (new_name, handle));
         Ok(())
     }
 
-    pub async fn join_room(&mut self, username: &str, room_name: &str) -> Result<(), ChatError> {
-        let room = self.rooms.get_mut(room_name).ok_or(ChatError {
-            kind: crate::common::ChatErrorKind::Room,
-            message: "Room does not exist".to_string(),
-        })?;
+    pub async fn delete_room(&self, username: &str, room_name: &str) -> Result<(), ChatError> {
+        let handle = self.start_change(room_name, None).await?;
 
-        if room.add_user(username.to_string()) {
-            self.broadcast_message(Message::new(
-                room_name.to_string(),
-                "System".to_string(),
-                format!("{} has joined the room", username),
-            ))
-            .await?;
-        }
+        let deleted = handle.delete(username).await;
+        let mut rooms = self.finish_change(room_name, None).await;
+        deleted?;
+        rooms.remove(&room_key(room_name));
         Ok(())
     }
 
-    pub async fn leave_room(&mut self, username: &str, room_name: &str) -> Result<(), ChatError> {
-        let room = self.rooms.get_mut(room_name).ok_or(ChatError {
-            kind: crate::common::ChatErrorKind::Room,
-            message: "Room does not exist".to_string(),
-        })?;
+    /// Adds the user to the room and returns the recent history to replay.
+    /// Invited users, moderators and the owner skip the room's mode checks.
+    pub async fn join_room(
+        &self,
+        username: &str,
+        room_name: &str,
+        password: Option<String>,
+    ) -> Result<Vec<Message>, ChatError> {
+        let room = self.room(room_name).await?;
+        room.join(username, password, self.history_replay).await
+    }
 
-        if room.remove_user(username) {
-            self.broadcast_message(Message::new(
-                room_name.to_string(),
-                "System".to_string(),
-                format!("{} has left the room", username),
-            ))
-            .await?;
+    pub async fn leave_room(&self, username: &str, room_name: &str) -> Result<(), ChatError> {
+        let content = format!("{} has left the room", username);
+        self.room(room_name).await?.leave(username, content).await
+    }
+
+    /// Takes a user who went away out of every room in `rooms`, telling the
+    /// remaining members `content`. Rooms deleted in the meantime are skipped.
+    pub async fn disconnect(
+        &self,
+        username: &str,
+        rooms: impl IntoIterator<Item = String>,
+        content: &str,
+    ) -> Result<(), ChatError> {
+        for room_name in rooms {
+            if let Ok(room) = self.room(&room_name).await {
+                room.leave(username, content.to_string()).await?;
+            }
         }
         Ok(())
     }
 
-    pub async fn broadcast_message(&mut self, message: Message) -> Result<(), ChatError> {
-        let room = self.rooms.get(&message.room).ok_or(ChatError {
-            kind: crate::common::ChatErrorKind::Room,
-            message: "Room does not exist".to_string(),
-        })?;
+    /// Makes `target` a moderator or a plain member. Only the owner may.
+    pub async fn set_role(
+        &self,
+        username: &str,
+        room_name: &str,
+        target: &str,
+        role: Role,
+    ) -> Result<(), ChatError> {
+        let room = self.room(room_name).await?;
+        room.set_role(username, target, role).await
+    }
+
+    pub async fn kick(
+        &self,
+        username: &str,
+        room_name: &str,
+        target: &str,
+        reason: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.room(room_name)
+            .await?
+            .kick(username, target, reason)
+            .await
+    }
+
+    /// Bans `target` from the room, for `duration` or until unbanned, and
+    /// removes them if they are in it.
+    pub async fn ban(
+        &self,
+        username: &str,
+        room_name: &str,
+        target: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), ChatError> {
+        self.room(room_name)
+            .await?
+            .ban(username, target, duration)
+            .await
+    }
+
+    pub async fn unban(
+        &self,
+        username: &str,
+        room_name: &str,
+        target: &str,
+    ) -> Result<(), ChatError> {
+        self.room(room_name).await?.unban(username, target).await
+    }
+
+    /// Lets `target` into the room whatever its mode, and tells them so.
+    pub async fn invite(
+        &self,
+        username: &str,
+        room_name: &str,
+        target: &str,
+    ) -> Result<(), ChatError> {
+        self.room(room_name).await?.invite(username, target).await
+    }
+
+    pub async fn set_mode(
+        &self,
+        username: &str,
+        room_name: &str,
+        change: ModeChange,
+    ) -> Result<(), ChatError> {
+        self.room(room_name).await?.set_mode(username, change).await
+    }
+
+    pub async fn broadcast_message(&self, message: Message) -> Result<(), ChatError> {
+        self.room(&message.room).await?.chat(message).await
+    }
 
-        for username in &room.users {
-            if let Some(client) = self.clients.get(username) {
-                client.send(message.clone()).await.map_err(|e| ChatError {
-                    kind: crate::common::ChatErrorKind::Message,
-                    message: e.to_string(),
-                })?;
+    /// Rooms visible to `username`: every room that is not hidden, plus the
+    /// hidden ones they are in, invited to or moderate.
+    pub async fn list_rooms(&self, username: &str) -> Vec<String> {
+        let rooms: Vec<(String, RoomHandle)> = self
+            .rooms
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+
+        let mut visible = Vec::new();
+        for (name, room) in rooms {
+            // A room deleted since is simply left out
+            if room.visible_to(username).await.unwrap_or(false) {
+                visible.push(name);
             }
         }
-        Ok(())
+        visible
     }
 
-    pub async fn list_rooms(&self) -> Vec<String> {
-        self.rooms.keys().cloned().collect()
+    /// A page of the room's history, see [`Room::history_page`]. Only members
+    /// of the room may read it.
+    pub async fn history(
+        &self,
+        username: &str,
+        room_name: &str,
+        limit: Option<usize>,
+        before: Option<u64>,
+    ) -> Result<Vec<Message>, ChatError> {
+        let limit = limit.unwrap_or(self.history_replay);
+        self.room(room_name)
+            .await?
+            .history(username, limit, before)
+            .await
     }
 
-    pub async fn list_users(&self, room_name: &str) -> Result<Vec<String>, ChatError> {
-        let room = self.rooms.get(room_name).ok_or(ChatError {
-            kind: crate::common::ChatErrorKind::Room,
-            message: "Room does not exist".to_string(),
-        })?;
-        Ok(room.users.clone())
+    /// Members of the room. Hidden rooms only list them to those they are
+    /// visible to, as with [`RoomManager::list_rooms`].
+    pub async fn list_users(
+        &self,
+        username: &str,
+        room_name: &str,
+    ) -> Result<Vec<String>, ChatError> {
+        self.room(room_name).await?.users(username).await
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> RoomManager {
+        RoomManager::new(Arc::new(Mutex::new(ClientManager::new())))
+    }
+
+    #[tokio::test]
+    async fn looks_rooms_up_regardless_of_case() {
+        let rooms = manager();
+        rooms.create_room("Lobby".to_string(), Some("alice".to_string())).await.unwrap();
+        let taken = rooms.create_room("LOBBY".to_string(), None).await.unwrap_err();
+        assert_eq!(taken.message, room_exists().message);
+        assert!(rooms.room("lobby").await.is_ok());
+        assert_eq!(rooms.room_name("lobby").await.as_deref(), Some("Lobby"));
+
+        // Respelling a room is not taking a name from another
+        rooms.rename_room("alice", "lobby", "LOBBY".to_string()).await.unwrap();
+        assert_eq!(rooms.list_rooms("alice").await, ["LOBBY"]);
+
+        let lookalike = rooms.create_room("caf\u{e9}".to_string(), None).await;
+        assert!(lookalike.unwrap_err().message.contains("ASCII"));
+    }
+
+    #[tokio::test]
+    async fn holds_both_names_while_a_room_is_renamed() {
+        let rooms = manager();
+        let alice = Some("alice".to_string());
+        rooms.create_room("lobby".to_string(), alice).await.unwrap();
+
+        rooms.start_change("lobby", Some("hall")).await.unwrap();
+        for name in ["hall", "HALL", "lobby"] {
+            let taken = rooms.create_room(name.to_string(), None).await.unwrap_err();
+            assert_eq!(taken.message, room_exists().message);
+        }
+        let busy = "Room is already being renamed or deleted";
+        let renamed = rooms.rename_room("alice", "lobby", "foyer".to_string()).await;
+        assert_eq!(renamed.unwrap_err().message, busy);
+        assert_eq!(rooms.delete_room("alice", "lobby").await.unwrap_err().message, busy);
+        // Nor can another room be renamed to either name meanwhile
+        rooms.create_room("attic".to_string(), Some("bob".to_string())).await.unwrap();
+        let renamed = rooms.rename_room("bob", "attic", "hall".to_string()).await;
+        assert_eq!(renamed.unwrap_err().message, room_exists().message);
+
+        // A change that came to nothing frees the new name again
+        drop(rooms.finish_change("lobby", Some("hall")).await);
+        rooms.create_room("hall".to_string(), None).await.unwrap();
+        rooms.delete_room("alice", "lobby").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lets_one_of_two_renames_to_the_same_name_through() {
+        let rooms = manager();
+        for room in ["red", "blue"] {
+            let owner = Some("alice".to_string());
+            rooms.create_room(room.to_string(), owner).await.unwrap();
+        }
+        let (red, blue) = tokio::join!(
+            rooms.rename_room("alice", "red", "purple".to_string()),
+            rooms.rename_room("alice", "blue", "purple".to_string()),
+        );
+        assert!(red.is_ok() != blue.is_ok());
+        let mut listed = rooms.list_rooms("alice").await;
+        listed.sort();
+        let kept = if red.is_ok() { "blue" } else { "red" };
+        assert_eq!(listed, [kept, "purple"]);
+    }
+
+    #[tokio::test]
+    async fn leaves_the_directory_alone_when_a_change_is_refused() {
+        let rooms = manager();
+        let alice = Some("alice".to_string());
+        rooms.create_room("lobby".to_string(), alice).await.unwrap();
+        assert!(rooms.rename_room("bob", "lobby", "hall".to_string()).await.is_err());
+        assert!(rooms.delete_room("bob", "lobby").await.is_err());
+        assert_eq!(rooms.list_rooms("bob").await, ["lobby"]);
+
+        // Neither name is left marked as changing
+        rooms.rename_room("alice", "lobby", "hall".to_string()).await.unwrap();
+        assert!(rooms.room("lobby").await.is_err());
+        rooms.delete_room("alice", "hall").await.unwrap();
+        assert!(rooms.list_rooms("alice").await.is_empty());
+    }
+}
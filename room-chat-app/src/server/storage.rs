@@ -0,0 +1,447 @@
+use crate::common::room::DEFAULT_MAX_HISTORY;
+use crate::common::{ChatError, ChatErrorKind, Message, Room};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+/// Records queued for the storage thread before rooms have to wait.
+const WRITE_QUEUE: usize = 1024;
+
+/// Where `RoomManager` keeps rooms, memberships and message history between
+/// restarts.
+pub trait Storage: Send + Sync {
+    /// Rebuilds every room recorded so far, history included.
+    fn load_rooms(&mut self) -> Result<Vec<Room>, ChatError>;
+
+    /// Records the room's current settings; members are not kept. Its history is
+    /// stored separately through `append_message`.
+    fn save_room(&mut self, room: &Room) -> Result<(), ChatError>;
+
+    fn rename_room(&mut self, old_name: &str, new_name: &str) -> Result<(), ChatError>;
+
+    fn delete_room(&mut self, name: &str) -> Result<(), ChatError>;
+
+    fn append_message(&mut self, message: &Message) -> Result<(), ChatError>;
+
+    /// Makes everything recorded so far durable.
+    fn flush(&mut self) -> Result<(), ChatError>;
+}
+
+/// What a `StorageWriter` asks its thread to record.
+enum StorageOp {
+    SaveRoom(Room),
+    RenameRoom { old_name: String, new_name: String },
+    DeleteRoom(String),
+    AppendMessage(Message),
+    Flush(oneshot::Sender<Result<(), ChatError>>),
+}
+
+/// Runs a `Storage` on a thread of its own, fed through a queue. Rooms hand
+/// their records over without waiting on the disk or on each other, and the
+/// records are written in the order they were handed over. Handles are cheap
+/// to clone; the thread ends once every handle is gone.
+///
+/// Writing fails on the thread, long after the room has moved on, so errors
+/// are logged there and the first one is also returned by the next `flush`.
+#[derive(Clone)]
+pub struct StorageWriter {
+    tx: mpsc::Sender<StorageOp>,
+}
+
+fn storage_stopped() -> ChatError {
+    ChatError {
+        kind: ChatErrorKind::Internal,
+        message: "Storage has stopped".to_string(),
+    }
+}
+
+impl StorageWriter {
+    pub fn spawn(mut storage: Box<dyn Storage>) -> Self {
+        let (tx, mut rx) = mpsc::channel(WRITE_QUEUE);
+        thread::spawn(move || {
+            let mut failed = None;
+            while let Some(op) = rx.blocking_recv() {
+                let result = match op {
+                    StorageOp::SaveRoom(room) => storage.save_room(&room),
+                    StorageOp::RenameRoom { old_name, new_name } => {
+                        storage.rename_room(&old_name, &new_name)
+                    }
+                    StorageOp::DeleteRoom(name) => storage.delete_room(&name),
+                    StorageOp::AppendMessage(message) => storage.append_message(&message),
+                    StorageOp::Flush(reply) => {
+                        let result = match failed.take() {
+                            Some(e) => Err(e),
+                            None => storage.flush(),
+                        };
+                        let _ = reply.send(result);
+                        continue;
+                    }
+                };
+                if let Err(e) = result {
+                    eprintln!("Storage error: {}", e);
+                    failed.get_or_insert(e);
+                }
+            }
+        });
+        StorageWriter { tx }
+    }
+
+    async fn send(&self, op: StorageOp) -> Result<(), ChatError> {
+        self.tx.send(op).await.map_err(|_| storage_stopped())
+    }
+
+    /// Queues the room's current settings, see [`Storage::save_room`].
+    pub async fn save_room(&self, room: &Room) -> Result<(), ChatError> {
+        self.send(StorageOp::SaveRoom(room.to_stored())).await
+    }
+
+    pub async fn rename_room(&self, old_name: &str, new_name: &str) -> Result<(), ChatError> {
+        self.send(StorageOp::RenameRoom {
+            old_name: old_name.to_string(),
+            new_name: new_name.to_string(),
+        })
+        .await
+    }
+
+    pub async fn delete_room(&self, name: &str) -> Result<(), ChatError> {
+        self.send(StorageOp::DeleteRoom(name.to_string())).await
+    }
+
+    pub async fn append_message(&self, message: &Message) -> Result<(), ChatError> {
+        self.send(StorageOp::AppendMessage(message.clone())).await
+    }
+
+    /// Waits until everything queued so far is written and durable.
+    pub async fn flush(&self) -> Result<(), ChatError> {
+        let (reply, done) = oneshot::channel();
+        self.send(StorageOp::Flush(reply)).await?;
+        done.await.map_err(|_| storage_stopped())?
+    }
+}
+
+/// Keeps nothing, for servers that do not need to survive a restart.
+#[derive(Default)]
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load_rooms(&mut self) -> Result<Vec<Room>, ChatError> {
+        Ok(Vec::new())
+    }
+
+    fn save_room(&mut self, _room: &Room) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    fn rename_room(&mut self, _old_name: &str, _new_name: &str) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    fn delete_room(&mut self, _name: &str) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    fn append_message(&mut self, _message: &Message) -> Result<(), ChatError> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ChatError> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record<'a> {
+    Room(Cow<'a, Room>),
+    RoomRenamed {
+        old_name: Cow<'a, str>,
+        new_name: Cow<'a, str>,
+    },
+    RoomDeleted(Cow<'a, str>),
+    Message(Cow<'a, Message>),
+}
+
+fn apply(rooms: &mut HashMap<String, Room>, record: Record, history_length: usize) {
+    match record {
+        Record::Room(room) => {
+            let mut room = room.into_owned();
+            if let Some(old) = rooms.remove(&room.name) {
+                room.history = old.history;
+            }
+            rooms.insert(room.name.clone(), room);
+        }
+        Record::RoomRenamed { old_name, new_name } => {
+            if let Some(mut room) = rooms.remove(old_name.as_ref()) {
+                room.name = new_name.to_string();
+                for message in room.history.iter_mut() {
+                    message.room = room.name.clone();
+                }
+                rooms.insert(room.name.clone(), room);
+            }
+        }
+        Record::RoomDeleted(name) => {
+            rooms.remove(name.as_ref());
+        }
+        Record::Message(message) => {
+            if let Some(room) = rooms.get_mut(&message.room) {
+                room.add_message(message.into_owned(), history_length);
+            }
+        }
+    }
+}
+
+/// An append-only log of JSON records, one per line.
+///
+/// Every record is handed to the OS as soon as it is written, so a crashed
+/// server loses only what was still queued for its `StorageWriter`; `flush`
+/// additionally syncs the file to disk. A record
+/// torn by a crash can only be the last line and is dropped on load. Loading
+/// also compacts the log down to the current state.
+pub struct LogStorage {
+    path: PathBuf,
+    file: BufWriter<File>,
+    history_length: usize,
+}
+
+fn open_for_append(path: &Path) -> Result<BufWriter<File>, ChatError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+impl LogStorage {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, ChatError> {
+        let path = path.into();
+        let file = open_for_append(&path)?;
+        Ok(LogStorage {
+            path,
+            file,
+            history_length: DEFAULT_MAX_HISTORY,
+        })
+    }
+
+    /// Sets how many messages per room are kept when loading the log.
+    pub fn with_history_length(mut self, history_length: usize) -> Self {
+        self.history_length = history_length;
+        self
+    }
+
+    fn write(&mut self, record: &Record) -> Result<(), ChatError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Rewrites the log so it holds just `rooms`, replacing the old file
+    /// atomically.
+    fn compact(&mut self, rooms: &[Room]) -> Result<(), ChatError> {
+        let tmp = self.path.with_extension("compact");
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            for room in rooms {
+                let records = std::iter::once(Record::Room(Cow::Borrowed(room)))
+                    .chain(room.history.iter().map(|m| Record::Message(Cow::Borrowed(m))));
+                for record in records {
+                    serde_json::to_writer(&mut out, &record)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            out.flush()?;
+            out.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.file = open_for_append(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Storage for LogStorage {
+    fn load_rooms(&mut self) -> Result<Vec<Room>, ChatError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut rooms = HashMap::new();
+        for (number, line) in data.split_inclusive(|b| *b == b'\n').enumerate() {
+            if !line.ends_with(b"\n") {
+                // Torn by a crash mid-write, nothing after it
+                break;
+            }
+            let record = serde_json::from_slice(line).map_err(|e| ChatError {
+                kind: ChatErrorKind::Internal,
+                message: format!(
+                    "Corrupt record on line {} of {}: {}",
+                    number + 1,
+                    self.path.display(),
+                    e
+                ),
+            })?;
+            apply(&mut rooms, record, self.history_length);
+        }
+
+        let rooms: Vec<Room> = rooms.into_values().collect();
+        self.compact(&rooms)?;
+        Ok(rooms)
+    }
+
+    fn save_room(&mut self, room: &Room) -> Result<(), ChatError> {
+        self.write(&Record::Room(Cow::Borrowed(room)))
+    }
+
+    fn rename_room(&mut self, old_name: &str, new_name: &str) -> Result<(), ChatError> {
+        self.write(&Record::RoomRenamed {
+            old_name: Cow::Borrowed(old_name),
+            new_name: Cow::Borrowed(new_name),
+        })
+    }
+
+    fn delete_room(&mut self, name: &str) -> Result<(), ChatError> {
+        self.write(&Record::RoomDeleted(Cow::Borrowed(name)))
+    }
+
+    fn append_message(&mut self, message: &Message) -> Result<(), ChatError> {
+        self.write(&Record::Message(Cow::Borrowed(message)))
+    }
+
+    fn flush(&mut self) -> Result<(), ChatError> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log file of its own for each test, removed if left over.
+    fn log_path(name: &str) -> PathBuf {
+        let file = format!("room-chat-{}-{}.log", name, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn message(room: &str, content: &str) -> Message {
+        Message::new(room.to_string(), "alice".to_string(), content.to_string())
+    }
+
+    /// The rooms in the log by name, each with its history's contents.
+    fn load(path: &Path, history_length: usize) -> Vec<(String, Vec<String>)> {
+        let mut storage = LogStorage::open(path)
+            .unwrap()
+            .with_history_length(history_length);
+        let mut rooms: Vec<_> = storage
+            .load_rooms()
+            .unwrap()
+            .into_iter()
+            .map(|room| {
+                for message in &room.history {
+                    assert_eq!(message.room, room.name);
+                }
+                let history = room.history.into_iter().map(|m| m.content).collect();
+                (room.name, history)
+            })
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    fn contents(contents: &[&str]) -> Vec<String> {
+        contents.iter().map(|content| content.to_string()).collect()
+    }
+
+    #[test]
+    fn drops_a_torn_last_line() {
+        let path = log_path("torn");
+        let mut storage = LogStorage::open(&path).unwrap();
+        storage.save_room(&Room::new("lobby".to_string())).unwrap();
+        storage.append_message(&message("lobby", "kept")).unwrap();
+        drop(storage);
+        // A crash in the middle of writing the next record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"Message":{"id":1,"room":"lob"#).unwrap();
+        drop(file);
+
+        let lobby = ("lobby".to_string(), contents(&["kept"]));
+        assert_eq!(load(&path, DEFAULT_MAX_HISTORY), vec![lobby.clone()]);
+        // Loading compacted the torn record away, so the log takes new ones
+        let mut storage = LogStorage::open(&path).unwrap();
+        storage.append_message(&message("lobby", "after")).unwrap();
+        drop(storage);
+        let lobby = ("lobby".to_string(), contents(&["kept", "after"]));
+        assert_eq!(load(&path, DEFAULT_MAX_HISTORY), vec![lobby]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_renames_deletions_and_messages() {
+        let path = log_path("replay");
+        let mut storage = LogStorage::open(&path).unwrap();
+        storage.save_room(&Room::new("lobby".to_string())).unwrap();
+        storage.save_room(&Room::new("games".to_string())).unwrap();
+        storage.append_message(&message("lobby", "one")).unwrap();
+        storage.append_message(&message("games", "gone")).unwrap();
+        storage.rename_room("lobby", "hall").unwrap();
+        storage.append_message(&message("hall", "two")).unwrap();
+        storage.delete_room("games").unwrap();
+        // Messages to rooms that no longer exist are ignored
+        storage.append_message(&message("games", "lost")).unwrap();
+        drop(storage);
+
+        let hall = ("hall".to_string(), contents(&["one", "two"]));
+        assert_eq!(load(&path, DEFAULT_MAX_HISTORY), vec![hall]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_history_length_messages_per_room() {
+        let path = log_path("trim");
+        let mut storage = LogStorage::open(&path).unwrap();
+        storage.save_room(&Room::new("lobby".to_string())).unwrap();
+        for content in ["1", "2", "3", "4", "5"] {
+            storage.append_message(&message("lobby", content)).unwrap();
+        }
+        drop(storage);
+
+        let lobby = ("lobby".to_string(), contents(&["3", "4", "5"]));
+        assert_eq!(load(&path, 3), vec![lobby]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compacts_to_a_log_that_reloads_the_same() {
+        let path = log_path("compact");
+        let mut storage = LogStorage::open(&path).unwrap();
+        let mut lobby = Room::new("lobby".to_string());
+        storage.save_room(&lobby).unwrap();
+        lobby.owner = Some("alice".to_string());
+        lobby.mode.invite_only = true;
+        storage.save_room(&lobby).unwrap();
+        storage.save_room(&Room::new("games".to_string())).unwrap();
+        storage.append_message(&message("lobby", "hi")).unwrap();
+        storage.rename_room("games", "play").unwrap();
+        storage.append_message(&message("play", "gg")).unwrap();
+        drop(storage);
+
+        let loaded = load(&path, DEFAULT_MAX_HISTORY);
+        // One record per room and per message remains
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 4);
+        assert_eq!(load(&path, DEFAULT_MAX_HISTORY), loaded);
+
+        let mut storage = LogStorage::open(&path).unwrap();
+        let rooms = storage.load_rooms().unwrap();
+        let lobby = rooms.iter().find(|room| room.name == "lobby").unwrap();
+        assert_eq!(lobby.owner.as_deref(), Some("alice"));
+        assert!(lobby.mode.invite_only);
+        fs::remove_file(&path).unwrap();
+    }
+}